@@ -11,7 +11,7 @@ use downloader_rs::download_status::DownloadStatus;
 
 #[tokio::main]
 async fn main() {
-    let mut service = DownloadService::new();
+    let service = DownloadService::new();
 
     let config = DownloadConfiguration::new()
         .set_url("https://httpbin.org/bytes/1024")
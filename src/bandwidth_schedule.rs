@@ -0,0 +1,147 @@
+use std::sync::Arc;
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+
+/// The source of the local wall-clock time a [`BandwidthSchedule`] is evaluated at.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The system's local time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/// The bandwidth a [`BandwidthSchedule`] allows the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bandwidth {
+    Unlimited,
+    /// At most this many bytes per second across all downloads.
+    Limited(u64),
+    /// No downloads run.
+    Paused,
+}
+
+struct Window {
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    bandwidth: Bandwidth,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveDateTime) -> bool {
+        let (day, at) = (time.weekday(), time.time());
+        if self.start <= self.end {
+            return self.days.contains(&day) && at >= self.start && at < self.end;
+        }
+        // The window wraps past midnight into the next day.
+        (self.days.contains(&day) && at >= self.start) || (self.days.contains(&day.pred()) && at < self.end)
+    }
+}
+
+/// Weekly time windows mapped to the bandwidth a
+/// [`DownloadService`](crate::download_service::DownloadService) may use.
+///
+/// The first window containing the current time applies, otherwise the default. A
+/// window whose end is before its start runs past midnight into the following day.
+///
+/// While the schedule is set, the service applies its bandwidth as the service-wide
+/// speed limit whenever the bandwidth changes. During a [`Bandwidth::Paused`] window no
+/// queued download starts and running downloads are stopped and requeued, to resume
+/// once the window ends. Streamed downloads cannot restart, so they keep running but
+/// receive nothing until the window ends.
+pub struct BandwidthSchedule {
+    windows: Vec<Window>,
+    default: Bandwidth,
+    clock: Arc<dyn Clock>,
+}
+
+impl BandwidthSchedule {
+    /// Create a schedule that allows `default` outside of its windows.
+    pub fn new(default: Bandwidth) -> Self {
+        Self {
+            windows: Vec::new(),
+            default,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Allow `bandwidth` on `days` from `start` until `end`.
+    pub fn add_window(mut self, days: &[Weekday], start: NaiveTime, end: NaiveTime, bandwidth: Bandwidth) -> Self {
+        self.windows.push(Window { days: days.to_vec(), start, end, bandwidth });
+        self
+    }
+
+    /// Read the time from `clock` instead of the system's local time.
+    pub fn set_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The bandwidth allowed at `time`.
+    pub fn bandwidth_at(&self, time: NaiveDateTime) -> Bandwidth {
+        self.windows.iter()
+            .find(|window| window.contains(time))
+            .map_or(self.default, |window| window.bandwidth)
+    }
+
+    /// The bandwidth allowed now.
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.bandwidth_at(self.clock.now())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use parking_lot::Mutex;
+
+    /// A clock that stays at the time it was last set to.
+    pub(crate) struct ManualClock(pub Mutex<NaiveDateTime>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> NaiveDateTime {
+            *self.0.lock()
+        }
+    }
+
+    pub(crate) fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-01-01 is a Monday.
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    pub(crate) fn hm(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_bandwidth_at() {
+        let weekdays = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri];
+        let schedule = BandwidthSchedule::new(Bandwidth::Unlimited)
+            .add_window(&weekdays, hm(12, 0), hm(13, 0), Bandwidth::Limited(4096))
+            .add_window(&weekdays, hm(9, 0), hm(17, 0), Bandwidth::Paused)
+            .add_window(&[Weekday::Sun], hm(22, 0), hm(2, 0), Bandwidth::Limited(1024));
+
+        assert_eq!(schedule.bandwidth_at(at(1, 8, 59)), Bandwidth::Unlimited);
+        assert_eq!(schedule.bandwidth_at(at(1, 9, 0)), Bandwidth::Paused);
+        assert_eq!(schedule.bandwidth_at(at(1, 12, 30)), Bandwidth::Limited(4096));
+        assert_eq!(schedule.bandwidth_at(at(5, 16, 59)), Bandwidth::Paused);
+        assert_eq!(schedule.bandwidth_at(at(5, 17, 0)), Bandwidth::Unlimited);
+        assert_eq!(schedule.bandwidth_at(at(6, 10, 0)), Bandwidth::Unlimited);
+        // The Sunday night window runs into Monday morning.
+        assert_eq!(schedule.bandwidth_at(at(7, 23, 0)), Bandwidth::Limited(1024));
+        assert_eq!(schedule.bandwidth_at(at(8, 1, 59)), Bandwidth::Limited(1024));
+        assert_eq!(schedule.bandwidth_at(at(8, 2, 0)), Bandwidth::Unlimited);
+
+        let clock = Arc::new(ManualClock(Mutex::new(at(2, 10, 0))));
+        let schedule = schedule.set_clock(clock.clone());
+        assert_eq!(schedule.bandwidth(), Bandwidth::Paused);
+        *clock.0.lock() = at(2, 18, 0);
+        assert_eq!(schedule.bandwidth(), Bandwidth::Unlimited);
+    }
+}
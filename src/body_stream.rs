@@ -0,0 +1,169 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc::{Receiver, UnboundedReceiver, UnboundedSender};
+use crate::error::DownloadError;
+
+/// Number of received buffers each chunk may hold before its download waits for the reader.
+pub(crate) const CHUNK_BUFFERS: usize = 16;
+
+/// What a chunk sends to the [`BodyStream`].
+pub(crate) enum ChunkItem {
+    Data(Bytes),
+    /// Every byte of the chunk has been sent.
+    End,
+}
+
+/// What the download sends to the [`BodyStream`], in body order.
+pub(crate) enum BodyPart {
+    /// The bytes of the next chunk.
+    Chunk(Receiver<ChunkItem>),
+    /// The download completed; no more chunks follow.
+    End,
+}
+
+pub(crate) fn channel() -> (UnboundedSender<BodyPart>, BodyStream) {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let stream = BodyStream {
+        parts: receiver,
+        current: None,
+        buffer: Bytes::new(),
+        done: false,
+    };
+    (sender, stream)
+}
+
+/// The body of a streamed download, in order, as it arrives.
+///
+/// Read it as a [`Stream`](futures::Stream) of [`Bytes`] or through [`AsyncRead`].
+/// Chunks of a chunked download are received concurrently and buffered until the
+/// reader reaches them; once a chunk's buffer is full its download waits, so a slow
+/// reader slows the download down instead of growing memory.
+///
+/// The stream ends with [`DownloadError::BodyStreamInterrupted`] if the download fails
+/// or is stopped; [`error`](crate::download_operation::DownloadOperation::error) then
+/// reports the cause. Dropping the stream fails the download with
+/// [`DownloadError::BodyStreamClosed`].
+pub struct BodyStream {
+    parts: UnboundedReceiver<BodyPart>,
+    current: Option<Receiver<ChunkItem>>,
+    /// Unread bytes of the last item, for [`AsyncRead`].
+    buffer: Bytes,
+    done: bool,
+}
+
+impl BodyStream {
+    fn poll_bytes(&mut self, cx: &mut Context<'_>) -> Poll<Option<crate::error::Result<Bytes>>> {
+        if !self.buffer.is_empty() {
+            return Poll::Ready(Some(Ok(std::mem::take(&mut self.buffer))));
+        }
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+            if let Some(current) = &mut self.current {
+                match current.poll_recv(cx) {
+                    Poll::Ready(Some(ChunkItem::Data(bytes))) => return Poll::Ready(Some(Ok(bytes))),
+                    Poll::Ready(Some(ChunkItem::End)) => self.current = None,
+                    Poll::Ready(None) => return self.interrupted(),
+                    Poll::Pending => return Poll::Pending,
+                }
+                continue;
+            }
+            match self.parts.poll_recv(cx) {
+                Poll::Ready(Some(BodyPart::Chunk(chunk))) => self.current = Some(chunk),
+                Poll::Ready(Some(BodyPart::End)) => self.done = true,
+                Poll::Ready(None) => return self.interrupted(),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn interrupted(&mut self) -> Poll<Option<crate::error::Result<Bytes>>> {
+        self.done = true;
+        Poll::Ready(Some(Err(DownloadError::BodyStreamInterrupted)))
+    }
+}
+
+impl futures::Stream for BodyStream {
+    type Item = crate::error::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_bytes(cx)
+    }
+}
+
+impl AsyncRead for BodyStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.buffer.is_empty() {
+            match this.poll_bytes(cx) {
+                Poll::Ready(Some(Ok(bytes))) => this.buffer = bytes,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(std::io::Error::other(e))),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = this.buffer.len().min(buf.remaining());
+        buf.put_slice(&this.buffer[..n]);
+        this.buffer.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use futures::StreamExt;
+    use tokio::io::AsyncReadExt;
+    use crate::download_configuration::DownloadConfiguration;
+    use crate::download_service::DownloadService;
+    use crate::download_status::DownloadStatus;
+    use crate::sink::tests::serve;
+
+    #[tokio::test]
+    async fn test_stream_chunked_body() {
+        let body: Arc<Vec<u8>> = Arc::new((0..300_000u32).map(|i| (i % 251) as u8).collect());
+        let url = serve(body.clone()).await;
+        let service = Arc::new(DownloadService::new());
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let config = DownloadConfiguration::new()
+            .set_url(&url)
+            .set_stream_body(true)
+            .set_chunk_download(true)
+            .set_chunk_size(32 * 1024)
+            .build()
+            .unwrap();
+        let operation = service.add_downloader(config);
+        let mut reader = operation.body().unwrap();
+        assert!(operation.body().is_none());
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, *body);
+        while !operation.is_done() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(operation.status(), DownloadStatus::Complete);
+
+        // A stopped download ends its stream with an error.
+        let config = DownloadConfiguration::new()
+            .set_url(&url)
+            .set_stream_body(true)
+            .build()
+            .unwrap();
+        let operation = service.add_downloader(config);
+        let mut stream = operation.body().unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        operation.stop();
+        let mut interrupted = false;
+        while let Some(item) = stream.next().await {
+            interrupted |= item.is_err();
+        }
+        assert!(interrupted);
+
+        handle.abort();
+    }
+}
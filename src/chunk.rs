@@ -5,45 +5,31 @@ use reqwest::Client;
 use tokio_util::sync::CancellationToken;
 use crate::download_task::DownloadTask;
 use crate::error::DownloadError;
-use crate::stream::Stream;
 use crate::chunk_range::ChunkRange;
+use crate::compression::Compression;
 use crate::download_configuration::DownloadConfiguration;
-use crate::download_sender::DownloadSender;
-use crate::rate_limiter::RateLimiter;
+use crate::download_url::DownloadUrl;
+use crate::rate_limiter::RateLimiterChain;
+use crate::sink::{DownloadSink, FileSink, MemoryBuffer, MemorySink};
 
-/// Represents a single download chunk, either file-backed or in-memory.
+/// A single download chunk, written through its [`DownloadSink`].
+#[derive(Default)]
 pub struct Chunk {
+    /// The chunk's file, for file downloads that can be resumed.
     pub file_path: Option<PathBuf>,
-    pub stream: Option<Stream>,
-    /// In-memory buffer — uses `Vec::with_capacity` + `extend_from_slice`
-    /// instead of zero-fill + Cursor for better performance.
-    pub bytes: Option<Vec<u8>>,
+    pub sink: Option<Box<dyn DownloadSink>>,
     pub chunk_range: ChunkRange,
     pub range_download: bool,
-    pub download_in_memory: bool,
     /// Shared global downloaded size counter (same Arc across all chunks of one download).
     pub downloaded_size: Option<Arc<AtomicU64>>,
     pub valid: bool,
 }
 
-impl Default for Chunk {
-    fn default() -> Self {
-        Self {
-            file_path: None,
-            stream: None,
-            bytes: None,
-            chunk_range: ChunkRange::default(),
-            range_download: false,
-            download_in_memory: false,
-            downloaded_size: None,
-            valid: false,
-        }
-    }
-}
-
 impl Chunk {
-    pub fn from_file(file_path: PathBuf, chunk_range: ChunkRange, range_download: bool) -> Self {
+    /// A chunk written to `file_path`, decoded with `decompression` if set.
+    pub fn from_file(file_path: PathBuf, chunk_range: ChunkRange, range_download: bool, decompression: Option<Compression>) -> Self {
         Self {
+            sink: Some(Box::new(FileSink::new(file_path.clone(), decompression))),
             file_path: Some(file_path),
             chunk_range,
             range_download,
@@ -51,10 +37,15 @@ impl Chunk {
         }
     }
 
-    pub fn from_memory(chunk_range: ChunkRange) -> Self {
+    pub fn from_memory(chunk_range: ChunkRange, range_download: bool, buffer: Arc<MemoryBuffer>) -> Self {
+        Self::from_sink(chunk_range, range_download, Box::new(MemorySink::new(buffer)))
+    }
+
+    pub fn from_sink(chunk_range: ChunkRange, range_download: bool, sink: Box<dyn DownloadSink>) -> Self {
         Self {
+            sink: Some(sink),
             chunk_range,
-            download_in_memory: true,
+            range_download,
             ..Default::default()
         }
     }
@@ -63,62 +54,61 @@ impl Chunk {
         self.downloaded_size = Some(counter);
     }
 
+    #[allow(clippy::needless_return)]
     pub fn get_downloaded_size(&self) -> u64 {
         return self.chunk_range.length();
     }
 
     pub async fn setup(&mut self) -> crate::error::Result<()> {
-        match self.download_in_memory {
-            true => {
-                let bytes = Vec::with_capacity(self.chunk_range.length() as usize);
-                self.bytes = Some(bytes);
-            }
-            false => {
-                let stream = Stream::new(self.file_path.as_ref().unwrap(), self.range_download).await?;
-                self.stream = Some(stream);
-            }
+        if let Some(sink) = &mut self.sink {
+            sink.open(self.range_download).await?;
         }
-
         Ok(())
     }
 
-    pub fn bytes(self) -> Option<Vec<u8>> {
-        if self.download_in_memory {
-            return self.bytes;
+    /// Discard the data received so far, for a retry that receives the whole body again.
+    pub async fn reset_async(&mut self) -> crate::error::Result<()> {
+        let received = self.chunk_range.position - self.chunk_range.start;
+        if let Some(counter) = &self.downloaded_size {
+            counter.fetch_sub(received, Ordering::Relaxed);
         }
-        None
+        self.chunk_range.position = self.chunk_range.start;
+        if let Some(sink) = &mut self.sink {
+            sink.open(false).await?;
+        }
+        Ok(())
     }
 
     pub async fn received_bytes_async(&mut self, buffer: &[u8]) -> crate::error::Result<()> {
-        let len = buffer.len() as u64;
-        match self.download_in_memory {
-            true => {
-                if let Some(vec) = &mut self.bytes {
-                    vec.extend_from_slice(buffer);
-                    self.chunk_range.position += len;
-                    if let Some(counter) = &self.downloaded_size {
-                        counter.fetch_add(len, Ordering::Relaxed);
-                    }
-                }
-            }
-            false => {
-                if let Some(stream) = &mut self.stream {
-                    stream.write_async(buffer).await?;
-                    self.chunk_range.position += len;
-                    if let Some(counter) = &self.downloaded_size {
-                        counter.fetch_add(len, Ordering::Relaxed);
-                    }
-                }
+        if let Some(sink) = &mut self.sink {
+            sink.write_at(self.chunk_range.position, buffer).await?;
+            let len = buffer.len() as u64;
+            self.chunk_range.position += len;
+            if let Some(counter) = &self.downloaded_size {
+                counter.fetch_add(len, Ordering::Relaxed);
             }
         }
         Ok(())
     }
 
     pub async fn flush_async(&mut self) -> crate::error::Result<()> {
-        if !self.download_in_memory {
-            if let Some(stream) = &mut self.stream {
-                stream.flush_async().await?;
-            }
+        if let Some(sink) = &mut self.sink {
+            sink.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Write out the remaining data once the whole range has been received.
+    pub async fn finish_async(&mut self) -> crate::error::Result<()> {
+        if let Some(sink) = &mut self.sink {
+            sink.finalize().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn abort_async(&mut self) -> crate::error::Result<()> {
+        if let Some(sink) = &mut self.sink {
+            sink.abort().await?;
         }
         Ok(())
     }
@@ -156,7 +146,7 @@ impl Chunk {
         }
 
         self.valid = false;
-        return 3;
+        3
     }
 }
 
@@ -164,16 +154,14 @@ pub async fn start_download(
     config: Arc<DownloadConfiguration>,
     client: Arc<Client>,
     mut chunk: Chunk,
-    sender: Arc<DownloadSender>,
     cancel_token: CancellationToken,
-    rate_limiter: Arc<RateLimiter>,
+    rate_limiter: RateLimiterChain,
+    download_url: Arc<DownloadUrl>,
 ) -> crate::error::Result<()> {
     let mut task = DownloadTask::new();
-    if let Err(e) = task.start_download(config, client, cancel_token, &mut chunk, rate_limiter).await {
-        return Err(e);
-    }
-    if chunk.download_in_memory {
-        let _ = sender.memory_sender.as_ref().unwrap().send(chunk.bytes().unwrap());
+    let result = task.start_download(config, client, cancel_token, &mut chunk, rate_limiter, download_url).await;
+    if result.is_err() {
+        let _ = chunk.abort_async().await;
     }
-    Ok(())
+    result
 }
\ No newline at end of file
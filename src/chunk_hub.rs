@@ -4,15 +4,21 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::fs;
 use tokio::fs::OpenOptions;
 use tokio::io::{AsyncWriteExt, BufReader};
-use crate::chunk_metadata;
+use tokio_util::sync::CancellationToken;
+use crate::{body_stream, chunk_metadata};
+use crate::body_stream::BodyPart;
+use crate::memory_budget::Reservation;
+use crate::sink::{MemoryBuffer, StreamSink};
 use crate::chunk::Chunk;
 use crate::chunk_range::ChunkRange;
 use crate::download_configuration::DownloadConfiguration;
+use crate::download_sender::DownloadSender;
 use crate::error::DownloadError;
 use crate::remote_file::RemoteFile;
 
+#[allow(clippy::suspicious_open_options)]
 pub async fn on_download_post(config: &Arc<DownloadConfiguration>, chunk_length: usize) -> crate::error::Result<()> {
-    if config.download_in_memory {
+    if !config.writes_file() {
         return Ok(());
     }
     if chunk_length > 1 {
@@ -33,12 +39,7 @@ pub async fn on_download_post(config: &Arc<DownloadConfiguration>, chunk_length:
                 return Err(DownloadError::FileFlush);
             }
 
-            for i in 0..chunk_length {
-                let chunk_path = chunk_file_path(config.get_file_path(), i);
-                if let Err(_e) = fs::remove_file(chunk_path).await {
-                    return Err(DownloadError::DeleteFile);
-                }
-            }
+            delete_chunk_files(config, chunk_length).await?;
         }
     }
 
@@ -47,23 +48,40 @@ pub async fn on_download_post(config: &Arc<DownloadConfiguration>, chunk_length:
     Ok(())
 }
 
+/// Delete the numbered chunk files of a download split into `chunk_length` chunks.
+pub async fn delete_chunk_files(config: &Arc<DownloadConfiguration>, chunk_length: usize) -> crate::error::Result<()> {
+    for i in 0..chunk_length {
+        let chunk_path = chunk_file_path(config.get_file_path(), i);
+        if let Err(_e) = fs::remove_file(chunk_path).await {
+            return Err(DownloadError::DeleteFile);
+        }
+    }
+    Ok(())
+}
+
 /// Validates existing chunks and sets up the shared downloaded_size counter.
 /// The counter is the same `Arc<AtomicU64>` from the sender, so the receiver
 /// can read progress at any time without polling.
 pub async fn validate(
     config: &Arc<DownloadConfiguration>,
     remote_file: RemoteFile,
-    downloaded_size_counter: Arc<AtomicU64>,
+    sender: &Arc<DownloadSender>,
+    cancel_token: &CancellationToken,
+    reservation: Reservation,
 ) -> crate::error::Result<Vec<Chunk>> {
+    let downloaded_size_counter = sender.downloaded_size.clone();
+    // A payload decoded while it is received can only be downloaded in one piece, from the start.
+    let streaming = config.is_streaming_decompression();
+    let range_download = config.range_download && remote_file.support_range_download && !streaming;
     let mut chunk_count = 1;
-    if config.range_download && remote_file.support_range_download && config.chunk_download && !config.download_in_memory {
+    if range_download && config.chunk_download {
         chunk_count = (remote_file.total_length as f64 / config.chunk_size as f64).ceil() as usize;
         chunk_count = chunk_count.max(1);
     }
 
-    let version = match config.download_in_memory {
-        true => 0,
-        false => chunk_metadata::get_local_version(config.get_file_path()).await,
+    let version = match config.writes_file() {
+        true => chunk_metadata::get_local_version(config.get_file_path()).await,
+        false => 0,
     };
     let remote_version = match config.remote_version {
         0 => remote_file.last_modified_time,
@@ -72,43 +90,41 @@ pub async fn validate(
 
     let chunk_ranges = ChunkRange::from_chunk_count(remote_file.total_length, chunk_count as u64, config.chunk_size);
 
+    let memory_buffer = match config.download_in_memory {
+        true => Some(MemoryBuffer::new(remote_file.total_length, chunk_count, reservation, sender.clone())),
+        false => None,
+    };
     let mut chunks = Vec::with_capacity(chunk_count);
     let mut initial_downloaded_total = 0u64;
 
     for i in 0..chunk_count {
-        let mut chunk = match config.download_in_memory {
-            true => {
-                Chunk::from_memory(chunk_ranges.get(i).unwrap().clone())
-            }
-            false => {
-                let file_path = match chunk_count {
-                    1 => config.get_file_temp_path().to_path_buf(),
-                    _ => chunk_file_path(config.get_file_path(), i),
-                };
-                Chunk::from_file(
-                    file_path,
-                    chunk_ranges.get(i).unwrap().clone(),
-                    config.range_download && remote_file.support_range_download,
-                )
+        let chunk_range = *chunk_ranges.get(i).unwrap();
+        let mut chunk = if let Some(factory) = &config.sink_factory {
+            let sink = factory.create(i, chunk_range.start, chunk_range.end)?;
+            Chunk::from_sink(chunk_range, range_download, sink)
+        } else if config.stream_body {
+            let (chunk_sender, chunk_receiver) = tokio::sync::mpsc::channel(body_stream::CHUNK_BUFFERS);
+            let body_sender = sender.body_sender.lock();
+            let Some(body_sender) = body_sender.as_ref() else {
+                return Err(DownloadError::BodyStreamClosed);
+            };
+            if body_sender.send(BodyPart::Chunk(chunk_receiver)).is_err() {
+                return Err(DownloadError::BodyStreamClosed);
             }
+            Chunk::from_sink(chunk_range, range_download, Box::new(StreamSink::new(chunk_sender, chunk_range.start, cancel_token.clone())))
+        } else if let Some(buffer) = &memory_buffer {
+            Chunk::from_memory(chunk_range, range_download, buffer.clone())
+        } else {
+            let file_path = match chunk_count {
+                1 => config.get_file_temp_path().to_path_buf(),
+                _ => chunk_file_path(config.get_file_path(), i),
+            };
+            let decompression = if streaming { config.decompression } else { None };
+            Chunk::from_file(file_path, chunk_range, range_download, decompression)
         };
 
-        if !config.download_in_memory {
-            match version != 0 && version == remote_version {
-                true => {
-                    match chunk.validate().await {
-                        2 => {
-                            chunk.delete_chunk_file().await?;
-                        }
-                        _ => {
-                            initial_downloaded_total += chunk.get_downloaded_size();
-                        }
-                    }
-                }
-                false => {
-                    chunk.delete_chunk_file().await?;
-                }
-            }
+        if config.writes_file() {
+            initial_downloaded_total += resume_or_reset(&mut chunk, version != 0 && version == remote_version && !streaming).await?;
         }
 
         chunk.set_downloaded_size_counter(downloaded_size_counter.clone());
@@ -118,14 +134,58 @@ pub async fn validate(
     // Set the initial downloaded total (from already-validated chunks)
     downloaded_size_counter.store(initial_downloaded_total, Ordering::Relaxed);
 
-    if !config.download_in_memory {
+    if config.writes_file() {
         chunk_metadata::save_local_version(config.get_file_path(), remote_version).await?;
     }
 
     Ok(chunks)
 }
 
+/// Set up one chunk file per range in `ranges`, as for a delta download that only
+/// requests parts of the remote file. Chunks of an interrupted download of the same
+/// `remote_version` are resumed.
+pub async fn validate_ranges(
+    config: &Arc<DownloadConfiguration>,
+    remote_version: i64,
+    ranges: Vec<ChunkRange>,
+    downloaded_size_counter: Arc<AtomicU64>,
+) -> crate::error::Result<Vec<Chunk>> {
+    let version = chunk_metadata::get_local_version(config.get_file_path()).await;
+    let mut chunks = Vec::with_capacity(ranges.len());
+    let mut initial_downloaded_total = 0u64;
+
+    for (i, range) in ranges.into_iter().enumerate() {
+        let mut chunk = Chunk::from_file(chunk_file_path(config.get_file_path(), i), range, true, None);
+        initial_downloaded_total += resume_or_reset(&mut chunk, version != 0 && version == remote_version).await?;
+        chunk.set_downloaded_size_counter(downloaded_size_counter.clone());
+        chunks.push(chunk);
+    }
+
+    downloaded_size_counter.store(initial_downloaded_total, Ordering::Relaxed);
+    chunk_metadata::save_local_version(config.get_file_path(), remote_version).await?;
+
+    Ok(chunks)
+}
+
+/// Pick up the data already in the chunk's file if `resume` is set, otherwise delete it.
+/// Returns the number of bytes kept.
+async fn resume_or_reset(chunk: &mut Chunk, resume: bool) -> crate::error::Result<u64> {
+    if resume {
+        match chunk.validate().await {
+            2 => {
+                chunk.delete_chunk_file().await?;
+            }
+            _ => {
+                return Ok(chunk.get_downloaded_size());
+            }
+        }
+        return Ok(0);
+    }
+    chunk.delete_chunk_file().await?;
+    Ok(0)
+}
+
 /// Build the path for a numbered chunk file, e.g. `/tmp/file.bin.chunk0`.
-fn chunk_file_path(base: &Path, index: usize) -> PathBuf {
+pub fn chunk_file_path(base: &Path, index: usize) -> PathBuf {
     PathBuf::from(format!("{}.chunk{}", base.display(), index))
 }
\ No newline at end of file
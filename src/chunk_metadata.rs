@@ -18,6 +18,7 @@ pub async fn get_local_version(path: impl AsRef<Path>) -> i64 {
     0
 }
 
+#[allow(clippy::redundant_pattern_matching, clippy::suspicious_open_options)]
 pub async fn save_local_version(path: impl AsRef<Path>, version: i64) -> crate::error::Result<()> {
     let meta_file_path = format!("{}.metadata", path.as_ref().display());
     let meta_path = Path::new(&meta_file_path);
@@ -34,10 +35,59 @@ pub async fn save_local_version(path: impl AsRef<Path>, version: i64) -> crate::
     Ok(())
 }
 
+#[allow(clippy::redundant_pattern_matching)]
 pub async fn delete_metadata(path: impl AsRef<Path>) -> crate::error::Result<()> {
     let meta_file_path = format!("{}.metadata", path.as_ref().display());
     if let Err(_) = tokio::fs::remove_file(meta_file_path).await {
         return Err(DownloadError::DeleteFile);
     };
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Cache validators of a completed download, sent back in conditional requests.
+#[derive(Default, Clone)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+pub async fn get_validators(path: impl AsRef<Path>) -> Option<Validators> {
+    let validators_file_path = format!("{}.validators", path.as_ref().display());
+    let content = fs::read_to_string(&validators_file_path).await.ok()?;
+    let mut lines = content.lines();
+    let mut next = || lines.next().filter(|line| !line.is_empty()).map(str::to_string);
+    let validators = Validators {
+        etag: next(),
+        last_modified: next(),
+    };
+    match validators.is_empty() {
+        true => None,
+        false => Some(validators),
+    }
+}
+
+pub async fn save_validators(path: impl AsRef<Path>, validators: &Validators) -> crate::error::Result<()> {
+    let validators_file_path = format!("{}.validators", path.as_ref().display());
+    let content = format!(
+        "{}\n{}\n",
+        validators.etag.as_deref().unwrap_or_default(),
+        validators.last_modified.as_deref().unwrap_or_default());
+    if fs::write(&validators_file_path, content).await.is_err() {
+        return Err(DownloadError::FileWrite);
+    }
+    Ok(())
+}
+
+pub async fn delete_validators(path: impl AsRef<Path>) -> crate::error::Result<()> {
+    let validators_file_path = format!("{}.validators", path.as_ref().display());
+    if fs::remove_file(&validators_file_path).await.is_err() {
+        return Err(DownloadError::DeleteFile);
+    }
+    Ok(())
+}
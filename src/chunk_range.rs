@@ -5,6 +5,7 @@ pub struct ChunkRange {
     pub position: u64,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for ChunkRange {
     fn default() -> Self {
         Self {
@@ -46,6 +47,7 @@ impl ChunkRange {
         chunk_ranges
     }
 
+    #[allow(clippy::needless_return)]
     pub fn chunk_length(&self) -> u64 {
         if self.end <= self.start {
             return 0u64;
@@ -53,6 +55,7 @@ impl ChunkRange {
         return self.end - self.start + 1;
     }
 
+    #[allow(clippy::needless_return)]
     pub fn length(&self) -> u64 {
         return self.position - self.start;
     }
@@ -61,6 +64,7 @@ impl ChunkRange {
         self.position = position;
     }
 
+    #[allow(clippy::needless_return)]
     pub fn eof(&self) -> bool {
         return self.position == self.end + 1;
     }
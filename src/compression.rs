@@ -0,0 +1,104 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufReader};
+use crate::error::DownloadError;
+
+/// Compression format of a downloaded payload.
+///
+/// Each codec is only available when the crate is built with its cargo feature
+/// (`gzip`, `zstd`, `brotli` or `xz`).
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Brotli,
+    Xz,
+}
+
+impl Compression {
+    /// Returns `true` if the crate was built with the feature for this codec.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Brotli => cfg!(feature = "brotli"),
+            Compression::Xz => cfg!(feature = "xz"),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Brotli => write!(f, "brotli"),
+            Compression::Xz => write!(f, "xz"),
+        }
+    }
+}
+
+/// Wrap `writer` so that compressed bytes written to it are decoded into `writer`.
+/// The decoder must be shut down to write the last decoded bytes.
+#[allow(unused_variables)]
+pub(crate) fn decoder<W>(compression: Compression, writer: W) -> crate::error::Result<Box<dyn AsyncWrite + Send + Sync + Unpin>>
+where
+    W: AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Ok(Box::new(async_compression::tokio::write::GzipDecoder::new(writer))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(Box::new(async_compression::tokio::write::ZstdDecoder::new(writer))),
+        #[cfg(feature = "brotli")]
+        Compression::Brotli => Ok(Box::new(async_compression::tokio::write::BrotliDecoder::new(writer))),
+        #[cfg(feature = "xz")]
+        Compression::Xz => Ok(Box::new(async_compression::tokio::write::XzDecoder::new(writer))),
+        #[allow(unreachable_patterns)]
+        _ => Err(DownloadError::Decompress(format!("{} support is not enabled", compression))),
+    }
+}
+
+/// Decode the `source` file into `target`, returning the decoded size.
+pub(crate) async fn decompress_file(compression: Compression, source: &Path, target: &Path) -> crate::error::Result<u64> {
+    let input = File::open(source).await.map_err(|_| DownloadError::FileOpen)?;
+    let output = File::create(target).await.map_err(|_| DownloadError::OpenOrCreateFile)?;
+    let mut reader = BufReader::with_capacity(64 * 1024, input);
+    let mut decoder = decoder(compression, output)?;
+    tokio::io::copy_buf(&mut reader, &mut decoder).await
+        .map_err(|e| DownloadError::Decompress(e.to_string()))?;
+    decoder.shutdown().await
+        .map_err(|e| DownloadError::Decompress(e.to_string()))?;
+    tokio::fs::metadata(target).await
+        .map(|metadata| metadata.len())
+        .map_err(|_| DownloadError::FileOpen)
+}
+
+#[cfg(all(test, feature = "gzip"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_decompress_file() {
+        let directory = std::env::temp_dir().join(format!("downloader-rs-compression-{}", std::process::id()));
+        tokio::fs::create_dir_all(&directory).await.unwrap();
+        let source = directory.join("data.gz");
+        let target = directory.join("data");
+
+        let mut encoder = async_compression::tokio::write::GzipEncoder::new(Vec::new());
+        encoder.write_all(&b"hello world ".repeat(1000)).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        tokio::fs::write(&source, encoder.into_inner()).await.unwrap();
+
+        let size = decompress_file(Compression::Gzip, &source, &target).await.unwrap();
+        assert_eq!(size, 12000);
+        assert_eq!(tokio::fs::read(&target).await.unwrap(), b"hello world ".repeat(1000));
+
+        tokio::fs::write(&source, b"not gzip").await.unwrap();
+        assert!(decompress_file(Compression::Gzip, &source, &target).await.is_err());
+
+        tokio::fs::remove_dir_all(&directory).await.unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+use futures::future::BoxFuture;
+
+/// Credentials sent in the `Authorization` header of every request of a download.
+#[derive(Clone)]
+pub enum Credentials {
+    /// HTTP Basic authentication.
+    Basic { username: String, password: Option<String> },
+    /// A bearer token, e.g. an OAuth access token.
+    Bearer(String),
+}
+
+/// Supplies credentials for a download and refreshes them when they expire.
+///
+/// The provider is asked for credentials before every request. `refresh` is `true`
+/// when the server rejected the previous attempt with `401 Unauthorized` or
+/// `403 Forbidden`, so the provider should obtain new credentials instead of
+/// returning cached ones. Chunks of one download may call it concurrently.
+///
+/// ```
+/// use futures::future::BoxFuture;
+/// use downloader_rs::credentials::{CredentialProvider, Credentials};
+///
+/// struct StaticToken(String);
+///
+/// impl CredentialProvider for StaticToken {
+///     fn credentials(&self, _refresh: bool) -> BoxFuture<'_, downloader_rs::error::Result<Credentials>> {
+///         Box::pin(async move { Ok(Credentials::Bearer(self.0.clone())) })
+///     }
+/// }
+/// ```
+pub trait CredentialProvider: Send + Sync {
+    fn credentials(&self, refresh: bool) -> BoxFuture<'_, crate::error::Result<Credentials>>;
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh64::xxh64;
+use crate::chunk_range::ChunkRange;
+use crate::error::DownloadError;
+
+/// How much of the local file is read at a time while searching for matching blocks.
+const READ_SIZE: usize = 1024 * 1024;
+
+/// Checksums of one block of a published file.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct BlockChecksum {
+    /// Rolling checksum, cheap to slide over the local file one byte at a time.
+    pub weak: u32,
+    /// xxHash64 (seed 0), confirming a weak match.
+    pub strong: u64,
+}
+
+/// Block checksums of a published file, as used for zsync-style delta downloads.
+///
+/// The publisher builds the index of each new file version with [`BlockIndex::from_reader`]
+/// and serves it next to the file, for example as JSON. A download configured with
+/// [`set_delta`](crate::download_configuration::DownloadConfigurationBuilder::set_delta)
+/// then copies the blocks it finds in an older local file and only requests the others.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct BlockIndex {
+    pub block_size: u32,
+    pub file_size: u64,
+    pub blocks: Vec<BlockChecksum>,
+}
+
+impl BlockIndex {
+    /// Build the index of the file read from `reader`.
+    pub fn from_reader(mut reader: impl Read, block_size: u32) -> std::io::Result<Self> {
+        let mut blocks = Vec::new();
+        let mut file_size = 0u64;
+        let mut buffer = vec![0u8; block_size as usize];
+        loop {
+            let mut filled = 0;
+            while filled < buffer.len() {
+                match reader.read(&mut buffer[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled == 0 {
+                break;
+            }
+            let block = &buffer[..filled];
+            blocks.push(BlockChecksum {
+                weak: Rolling::new(block).digest(),
+                strong: xxh64(block, 0),
+            });
+            file_size += filled as u64;
+            if filled < buffer.len() {
+                break;
+            }
+        }
+        Ok(Self {
+            block_size,
+            file_size,
+            blocks,
+        })
+    }
+
+    fn block_range(&self, index: usize) -> (u64, u64) {
+        let start = index as u64 * self.block_size as u64;
+        let end = (start + self.block_size as u64).min(self.file_size);
+        (start, end)
+    }
+}
+
+/// rsync-style rolling checksum over a fixed-size window.
+struct Rolling {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let length = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((length - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, length }
+    }
+
+    /// Slide the window one byte forward, dropping `out` and adding `new`.
+    fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self.b.wrapping_sub(self.length.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// For each block of the published file, the offset of an identical block in the local file.
+pub(crate) struct DeltaPlan {
+    matched: Vec<Option<u64>>,
+}
+
+impl DeltaPlan {
+    /// Number of bytes that are copied from the local file.
+    pub fn reused_size(&self, index: &BlockIndex) -> u64 {
+        (0..self.matched.len())
+            .filter(|&i| self.matched[i].is_some())
+            .map(|i| {
+                let (start, end) = index.block_range(i);
+                end - start
+            })
+            .sum()
+    }
+
+    /// Byte ranges of the published file that have to be downloaded, with adjacent
+    /// missing blocks merged into one range.
+    pub fn missing_ranges(&self, index: &BlockIndex) -> Vec<ChunkRange> {
+        let mut ranges: Vec<ChunkRange> = Vec::new();
+        for (i, matched) in self.matched.iter().enumerate() {
+            if matched.is_some() {
+                continue;
+            }
+            let (start, end) = index.block_range(i);
+            match ranges.last_mut() {
+                Some(last) if last.end + 1 == start => last.end = end - 1,
+                _ => ranges.push(ChunkRange::from_start_end(start, end - 1)),
+            }
+        }
+        ranges
+    }
+}
+
+/// Find the blocks of `index` that the local file at `base` already contains.
+pub(crate) fn plan(index: &BlockIndex, base: &Path) -> crate::error::Result<DeltaPlan> {
+    let mut file = File::open(base).map_err(|_| DownloadError::FileOpen)?;
+    let block_size = index.block_size as usize;
+    let mut matched = vec![None; index.blocks.len()];
+
+    // Only full blocks are searched for; a shorter last block is compared with the end
+    // of the local file below.
+    let mut candidates: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in index.blocks.iter().enumerate() {
+        let (start, end) = index.block_range(i);
+        if (end - start) as usize == block_size {
+            candidates.entry(block.weak).or_default().push(i);
+        }
+    }
+    let mut remaining: usize = candidates.values().map(Vec::len).sum();
+
+    let mut buffer = Vec::with_capacity(READ_SIZE + block_size);
+    let mut buffer_offset = 0u64;
+    let mut position = 0usize;
+    let mut rolling: Option<Rolling> = None;
+    let mut eof = false;
+
+    while remaining > 0 && block_size > 0 {
+        // Keep one byte past the window available so it can be rolled.
+        if buffer.len() < position + block_size + 1 && !eof {
+            buffer.drain(..position);
+            buffer_offset += position as u64;
+            position = 0;
+            let length = buffer.len();
+            buffer.resize(length + READ_SIZE, 0);
+            let n = file.read(&mut buffer[length..]).map_err(|_| DownloadError::FileOpen)?;
+            buffer.truncate(length + n);
+            eof = n == 0;
+            continue;
+        }
+        if buffer.len() < position + block_size {
+            break;
+        }
+
+        let window = &buffer[position..position + block_size];
+        let checksum = rolling.get_or_insert_with(|| Rolling::new(window));
+        if let Some(blocks) = candidates.get(&checksum.digest()) {
+            let strong = xxh64(window, 0);
+            let mut hit = false;
+            for &i in blocks {
+                if matched[i].is_none() && index.blocks[i].strong == strong {
+                    matched[i] = Some(buffer_offset + position as u64);
+                    remaining -= 1;
+                    hit = true;
+                }
+            }
+            if hit {
+                position += block_size;
+                rolling = None;
+                continue;
+            }
+        }
+
+        if buffer.len() < position + block_size + 1 {
+            break;
+        }
+        checksum.roll(buffer[position], buffer[position + block_size]);
+        position += 1;
+    }
+
+    if let Some(last) = index.blocks.len().checked_sub(1) {
+        let (start, end) = index.block_range(last);
+        let length = end - start;
+        let local_size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        if matched[last].is_none() && length < block_size as u64 && local_size >= length {
+            let mut tail = vec![0u8; length as usize];
+            let offset = local_size - length;
+            if file.seek(SeekFrom::Start(offset)).is_ok() && file.read_exact(&mut tail).is_ok()
+                && xxh64(&tail, 0) == index.blocks[last].strong {
+                matched[last] = Some(offset);
+            }
+        }
+    }
+
+    Ok(DeltaPlan { matched })
+}
+
+/// Write the published file to `target` from the blocks of `base` and the downloaded
+/// `ranges`, stored in `range_files`. Every block is checked against the index.
+pub(crate) fn assemble(
+    index: &BlockIndex,
+    plan: &DeltaPlan,
+    base: &Path,
+    ranges: &[ChunkRange],
+    range_files: &[std::path::PathBuf],
+    target: &Path) -> crate::error::Result<()> {
+    let mut base = File::open(base).map_err(|_| DownloadError::FileOpen)?;
+    let mut output = BufWriter::with_capacity(READ_SIZE, File::create(target).map_err(|_| DownloadError::OpenOrCreateFile)?);
+    let mut downloaded = ranges.iter().zip(range_files).peekable();
+    let mut range_reader: Option<BufReader<File>> = None;
+    let mut block = vec![0u8; index.block_size as usize];
+
+    for (i, checksum) in index.blocks.iter().enumerate() {
+        let (start, end) = index.block_range(i);
+        let block = &mut block[..(end - start) as usize];
+        match plan.matched[i] {
+            Some(offset) => {
+                base.seek(SeekFrom::Start(offset)).map_err(|_| DownloadError::FileSeek)?;
+                base.read_exact(block).map_err(|_| DownloadError::FileOpen)?;
+            }
+            None => {
+                // Blocks are visited in order, so each range file is read front to back.
+                while let Some((range, path)) = downloaded.peek() {
+                    if start > range.end {
+                        downloaded.next();
+                        range_reader = None;
+                        continue;
+                    }
+                    if range_reader.is_none() {
+                        range_reader = Some(BufReader::new(File::open(path).map_err(|_| DownloadError::FileOpen)?));
+                    }
+                    break;
+                }
+                let reader = range_reader.as_mut().ok_or(DownloadError::FileOpen)?;
+                reader.read_exact(block).map_err(|_| DownloadError::FileOpen)?;
+            }
+        }
+        if xxh64(block, 0) != checksum.strong {
+            return Err(DownloadError::FileVerify);
+        }
+        output.write_all(block).map_err(|_| DownloadError::FileWrite)?;
+    }
+
+    output.flush().map_err(|_| DownloadError::FileFlush)?;
+    output.get_ref().sync_all().map_err(|_| DownloadError::FileFlush)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data: Vec<u8> = (0..200u32).map(|i| (i * 7 % 256) as u8).collect();
+        let mut rolling = Rolling::new(&data[0..64]);
+        for i in 1..100 {
+            rolling.roll(data[i - 1], data[i + 63]);
+            assert_eq!(rolling.digest(), Rolling::new(&data[i..i + 64]).digest());
+        }
+    }
+
+    #[test]
+    fn test_delta_plan_and_assemble() {
+        let directory = std::env::temp_dir().join(format!("downloader-rs-delta-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        // The new file inserts 3 bytes near the start and changes one block in the middle.
+        let old: Vec<u8> = (0..40_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut new = old[..100].to_vec();
+        new.extend_from_slice(b"xyz");
+        new.extend_from_slice(&old[100..]);
+        new[20_000..20_010].copy_from_slice(b"0123456789");
+        std::fs::write(directory.join("old"), &old).unwrap();
+
+        let index = BlockIndex::from_reader(&new[..], 1024).unwrap();
+        assert_eq!(index.file_size, new.len() as u64);
+        let plan = plan(&index, &directory.join("old")).unwrap();
+        let ranges = plan.missing_ranges(&index);
+        // The first block (insertion) and the changed block are downloaded.
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].start, 0);
+        assert_eq!(plan.reused_size(&index), new.len() as u64 - 2048);
+
+        let mut range_files = Vec::new();
+        for (i, range) in ranges.iter().enumerate() {
+            let path = directory.join(format!("range{}", i));
+            std::fs::write(&path, &new[range.start as usize..=range.end as usize]).unwrap();
+            range_files.push(path);
+        }
+        assemble(&index, &plan, &directory.join("old"), &ranges, &range_files, &directory.join("new")).unwrap();
+        assert_eq!(std::fs::read(directory.join("new")).unwrap(), new);
+
+        std::fs::write(&range_files[0], vec![0u8; ranges[0].chunk_length() as usize]).unwrap();
+        let result = assemble(&index, &plan, &directory.join("old"), &ranges, &range_files, &directory.join("new"));
+        assert!(matches!(result, Err(DownloadError::FileVerify)));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::Mutex;
+use xxhash_rust::xxh64;
+use crate::error::DownloadError;
+use crate::verify::file_verify::FileVerify;
+
+/// Suffix of the marker file whose modification time records when an entry was last used.
+const ACCESS_SUFFIX: &str = ".access";
+/// Suffix of files that are still being written into the cache.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+static PARTIAL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Content-addressed cache of downloaded files shared by a
+/// [`DownloadService`](crate::download_service::DownloadService).
+///
+/// Entries are keyed by the expected [`FileVerify`] hash when one is configured, which
+/// lets a download be served from the cache without any request, or otherwise by the
+/// URL and the `ETag` reported by the server. Files are copied into and out of the cache,
+/// so editing a downloaded file never changes the cached entry. When `max_size` is non-zero the least recently
+/// used entries are evicted after each insert until the cache fits.
+pub struct DownloadCache {
+    directory: PathBuf,
+    max_size: u64,
+    eviction: Mutex<()>,
+}
+
+impl DownloadCache {
+    /// Create a cache in `directory` holding at most `max_size` bytes. 0 means unbounded.
+    pub fn new(directory: impl AsRef<Path>, max_size: u64) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            max_size,
+            eviction: Mutex::new(()),
+        }
+    }
+
+    /// The directory holding the cache entries.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// The cache key for a download with a known hash, if it has one.
+    pub(crate) fn hash_key(file_verify: &FileVerify) -> Option<String> {
+        match file_verify {
+            FileVerify::None => None,
+            FileVerify::xxHash(hash) => Some(format!("xxh64-{:016x}", hash)),
+        }
+    }
+
+    /// The cache key for a URL whose server reported `etag`.
+    pub(crate) fn etag_key(url: &str, etag: &str) -> String {
+        let mut hasher = xxh64::Xxh64::new(0);
+        hasher.update(url.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(etag.as_bytes());
+        format!("etag-{:016x}", hasher.digest())
+    }
+
+    /// Copy the entry for `key` to `temp_path`, from where it is placed at the destination
+    /// like a finished download. Returns the entry size on a hit.
+    pub(crate) async fn fetch(&self, key: &str, temp_path: &Path) -> Option<u64> {
+        let entry = self.directory.join(key);
+        let metadata = fs::metadata(&entry).await.ok()?;
+
+        let _ = fs::remove_file(temp_path).await;
+        if fs::copy(&entry, temp_path).await.is_err() {
+            let _ = fs::remove_file(temp_path).await;
+            return None;
+        }
+
+        touch(&self.directory.join(format!("{}{}", key, ACCESS_SUFFIX))).await;
+        tracing::info!(key, "served download from cache");
+        Some(metadata.len())
+    }
+
+    /// Store the file at `source` under `key` and evict old entries if the cache is full.
+    pub(crate) async fn insert(&self, key: &str, source: &Path) -> crate::error::Result<()> {
+        if fs::create_dir_all(&self.directory).await.is_err() {
+            return Err(DownloadError::OpenOrCreateFile);
+        }
+
+        let entry = self.directory.join(key);
+        let partial = self.directory.join(format!(
+            "{}.{}-{}{}",
+            key,
+            std::process::id(),
+            PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed),
+            PARTIAL_SUFFIX));
+        if fs::copy(source, &partial).await.is_err() {
+            let _ = fs::remove_file(&partial).await;
+            return Err(DownloadError::FileWrite);
+        }
+        if let Err(e) = fs::rename(&partial, &entry).await {
+            let _ = fs::remove_file(&partial).await;
+            return Err(DownloadError::FileRename(format!("cache insert failed {}", e)));
+        }
+        touch(&self.directory.join(format!("{}{}", key, ACCESS_SUFFIX))).await;
+        tracing::debug!(key, "inserted download into cache");
+
+        self.evict().await;
+        Ok(())
+    }
+
+    async fn evict(&self) {
+        if self.max_size == 0 {
+            return;
+        }
+        let _guard = self.eviction.lock().await;
+
+        let Ok(mut dir) = fs::read_dir(&self.directory).await else {
+            return;
+        };
+        let mut entries: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+        let mut total_size = 0u64;
+        while let Ok(Some(item)) = dir.next_entry().await {
+            let name = item.file_name().to_string_lossy().into_owned();
+            if name.ends_with(ACCESS_SUFFIX) || name.ends_with(PARTIAL_SUFFIX) {
+                continue;
+            }
+            let Ok(metadata) = item.metadata().await else {
+                continue;
+            };
+            let access = self.directory.join(format!("{}{}", name, ACCESS_SUFFIX));
+            let last_used = match fs::metadata(&access).await {
+                Ok(access) => access.modified().ok(),
+                Err(_) => metadata.modified().ok(),
+            }.unwrap_or(SystemTime::UNIX_EPOCH);
+            total_size += metadata.len();
+            entries.push((last_used, metadata.len(), item.path()));
+        }
+
+        entries.sort_by_key(|(last_used, _, _)| *last_used);
+        for (_, size, path) in entries {
+            if total_size <= self.max_size {
+                break;
+            }
+            if fs::remove_file(&path).await.is_ok() {
+                let _ = fs::remove_file(format!("{}{}", path.display(), ACCESS_SUFFIX)).await;
+                total_size -= size;
+                tracing::debug!(entry = %path.display(), "evicted cache entry");
+            }
+        }
+    }
+}
+
+async fn touch(path: &Path) {
+    let path = path.to_path_buf();
+    let _ = tokio::task::spawn_blocking(move || {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+    }).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_insert_fetch_evict() {
+        let root = std::env::temp_dir().join(format!("downloader-rs-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root).await;
+        fs::create_dir_all(&root).await.unwrap();
+        let cache = DownloadCache::new(root.join("cache"), 10);
+
+        let source = root.join("source.bin");
+        fs::write(&source, b"012345").await.unwrap();
+        cache.insert("a", &source).await.unwrap();
+        std::fs::File::options().write(true).open(root.join("cache").join("a.access")).unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        cache.insert("b", &source).await.unwrap();
+        // 12 bytes exceed the 10 byte budget, so the older entry is evicted.
+        assert!(fs::metadata(root.join("cache").join("a")).await.is_err());
+
+        let temp = root.join("destination.bin.temp");
+        assert_eq!(cache.fetch("a", &temp).await, None);
+        assert_eq!(cache.fetch("b", &temp).await, Some(6));
+        assert_eq!(fs::read(&temp).await.unwrap(), b"012345");
+
+        // Editing the source or a fetched file in place leaves the entry intact.
+        fs::write(&source, b"xxxxxx").await.unwrap();
+        fs::write(&temp, b"yyyyyy").await.unwrap();
+        assert_eq!(fs::read(root.join("cache").join("b")).await.unwrap(), b"012345");
+
+        let _ = fs::remove_dir_all(&root).await;
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_backs_up_existing_file() {
+        use std::sync::Arc;
+        use crate::download_configuration::{DownloadConfiguration, ExistingFilePolicy};
+        use crate::download_outcome::DownloadOutcome;
+        use crate::download_service::DownloadService;
+        use crate::existing_file::backup_path;
+        use crate::sink::tests::serve;
+
+        let root = std::env::temp_dir().join(format!("downloader-rs-cache-hit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root).await;
+        let body: Arc<Vec<u8>> = Arc::new((0..50_000u32).map(|i| (i % 253) as u8).collect());
+        let url = serve(body.clone()).await;
+        let service = Arc::new(DownloadService::new());
+        service.set_cache(DownloadCache::new(root.join("cache"), 0));
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let download = |path: PathBuf| {
+            let config = DownloadConfiguration::new()
+                .set_url(&url)
+                .set_file_path(path)
+                .set_file_verify(FileVerify::xxHash(xxh64::xxh64(&body, 0)))
+                .set_existing_file_policy(ExistingFilePolicy::Backup)
+                .build()
+                .unwrap();
+            service.add_downloader(config)
+        };
+        let wait = |operation: crate::download_operation::DownloadOperation| async move {
+            while !operation.is_done() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            operation.outcome()
+        };
+        assert_eq!(wait(download(root.join("first.bin"))).await, Some(DownloadOutcome::Downloaded));
+
+        let destination = root.join("second.bin");
+        fs::write(&destination, b"old").await.unwrap();
+        assert_eq!(wait(download(destination.clone())).await, Some(DownloadOutcome::Cached));
+        assert_eq!(fs::read(&destination).await.unwrap(), *body);
+        assert_eq!(fs::read(backup_path(&destination)).await.unwrap(), b"old");
+
+        handle.abort();
+        let _ = fs::remove_dir_all(&root).await;
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(DownloadCache::hash_key(&FileVerify::xxHash(0xff)).unwrap(), "xxh64-00000000000000ff");
+        assert!(DownloadCache::hash_key(&FileVerify::None).is_none());
+        assert_ne!(DownloadCache::etag_key("https://a/x", "\"1\""), DownloadCache::etag_key("https://a/x", "\"2\""));
+    }
+}
@@ -1,35 +1,99 @@
 use std::path::{Path, PathBuf};
-use crate::verify::file_verify::FileVerify;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::compression::Compression;
+use crate::delta::BlockIndex;
+use crate::credentials::{CredentialProvider, Credentials};
+use crate::extract::ArchiveFormat;
+use crate::patch::PatchFormat;
+use crate::service_configuration::ProxyConfiguration;
+use crate::sink::SinkFactory;
+use crate::url_provider::UrlProvider;
+use crate::verify::file_verify::{FileVerify, VerifyTarget};
 use crate::error::DownloadError;
 
+/// What to do when the destination of a file download already exists.
+///
+/// The policy is evaluated before the download starts and again before the finished
+/// file is moved into place, in case a file appeared there in the meantime.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub enum ExistingFilePolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Keep the existing file and complete with
+    /// [`DownloadOutcome::Skipped`](crate::download_outcome::DownloadOutcome::Skipped).
+    Skip,
+    /// Keep the existing file if it matches the configured hash or, without one, the size
+    /// reported by the server; otherwise replace it.
+    SkipIfSame,
+    /// Fail with [`DownloadError::FileExists`].
+    Fail,
+    /// Keep both by downloading to a free name with a numbered suffix, such as `file (1).zip`.
+    Rename,
+    /// Move the existing file to `<path>.bak` before replacing it.
+    Backup,
+}
+
 /// Configuration for a single download operation.
 ///
 /// Use the builder pattern via [`DownloadConfiguration::new()`] to construct.
+#[derive(Clone)]
 pub struct DownloadConfiguration {
     pub url: Option<String>,
     pub temp_path: Option<PathBuf>,
     pub path: Option<PathBuf>,
+    pub directory: Option<PathBuf>,
+    pub default_file_name: String,
+    pub existing_file_policy: ExistingFilePolicy,
     pub chunk_size: u64,
     pub total_length: u64,
     pub remote_version: i64,
     pub retry_times_on_failure: u8,
     pub receive_bytes_per_second: u64,
+    /// Bytes the download may receive at once after a pause, 0 for one second's worth.
+    pub receive_burst_bytes: u64,
     pub timeout: u64,
     pub range_download: bool,
     pub chunk_download: bool,
     pub download_in_memory: bool,
+    pub conditional_download: bool,
     pub file_verify: FileVerify,
+    pub verify_target: VerifyTarget,
+    pub decompression: Option<Compression>,
+    pub streaming_decompression: bool,
+    pub extract_to: Option<PathBuf>,
+    pub archive_format: Option<ArchiveFormat>,
+    pub remove_archive: bool,
+    pub patch_base: Option<PathBuf>,
+    pub patch_format: Option<PatchFormat>,
+    pub delta_base: Option<PathBuf>,
+    pub delta_index: Option<Arc<BlockIndex>>,
+    pub headers: HeaderMap,
+    pub cookies: Vec<(String, String)>,
+    pub user_agent: Option<String>,
+    pub credentials: Option<Credentials>,
+    pub credential_provider: Option<Arc<dyn CredentialProvider>>,
+    pub proxy: Option<ProxyConfiguration>,
+    pub url_provider: Option<Arc<dyn UrlProvider>>,
+    pub sink_factory: Option<Arc<dyn SinkFactory>>,
+    pub stream_body: bool,
+    pub url_expiry: u64,
+    pub max_redirects: usize,
 }
 
 /// Builder for [`DownloadConfiguration`].
 pub struct DownloadConfigurationBuilder {
     config: DownloadConfiguration,
+    invalid_header: Option<String>,
 }
 
 impl DownloadConfigurationBuilder {
     fn new(config: DownloadConfiguration) -> Self {
         Self {
-            config
+            config,
+            invalid_header: None,
         }
     }
 
@@ -49,6 +113,25 @@ impl DownloadConfigurationBuilder {
         self
     }
 
+    /// Download into `directory`, naming the file after the `Content-Disposition` header
+    /// or the last segment of the final URL. Ignored if a file path is set.
+    pub fn set_directory(mut self, directory: impl AsRef<Path>) -> DownloadConfigurationBuilder {
+        self.config.directory = Some(directory.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the file name used in directory mode when the server and URL provide none.
+    pub fn set_default_file_name(mut self, file_name: &str) -> DownloadConfigurationBuilder {
+        self.config.default_file_name = file_name.to_string();
+        self
+    }
+
+    /// Set what happens when the destination file already exists.
+    pub fn set_existing_file_policy(mut self, policy: ExistingFilePolicy) -> DownloadConfigurationBuilder {
+        self.config.existing_file_policy = policy;
+        self
+    }
+
     /// Set the remote version for cache validation.
     pub fn set_remote_version(mut self, version: i64) -> DownloadConfigurationBuilder {
         self.config.remote_version = version;
@@ -85,37 +168,260 @@ impl DownloadConfigurationBuilder {
         self
     }
 
-    /// Set the global download speed limit in bytes per second. 0 means unlimited.
+    /// Set this download's speed limit in bytes per second. 0 means unlimited.
     pub fn set_download_speed_limit(mut self, receive_bytes_per_second: u64) -> DownloadConfigurationBuilder {
         self.config.receive_bytes_per_second = receive_bytes_per_second;
         self
     }
 
-    /// Enable or disable in-memory downloads (result accessible via `DownloadOperation::bytes()`).
+    /// Set how many bytes the download may receive at once after a pause, above its speed
+    /// limit. 0, the default, allows one second's worth.
+    pub fn set_download_speed_burst(mut self, receive_burst_bytes: u64) -> DownloadConfigurationBuilder {
+        self.config.receive_burst_bytes = receive_burst_bytes;
+        self
+    }
+
+    /// Enable or disable in-memory downloads (result accessible via `DownloadOperation::into_bytes()`).
     pub fn set_download_in_memory(mut self, download_in_memory: bool) -> DownloadConfigurationBuilder {
         self.config.download_in_memory = download_in_memory;
         self
     }
 
+    /// Enable or disable conditional downloads. When enabled, the `ETag` and `Last-Modified`
+    /// of a completed download are kept in a `.validators` file next to it, and a later
+    /// download of the same file sends them as `If-None-Match` / `If-Modified-Since`.
+    /// If the server answers `304 Not Modified` the local file is kept and the download
+    /// completes with [`DownloadOutcome::UpToDate`](crate::download_outcome::DownloadOutcome::UpToDate).
+    pub fn set_conditional_download(mut self, conditional_download: bool) -> DownloadConfigurationBuilder {
+        self.config.conditional_download = conditional_download;
+        self
+    }
+
+    /// Add a header sent with every request of this download.
+    /// Invalid header names or values are reported by [`build`](Self::build).
+    pub fn set_header(mut self, name: &str, value: &str) -> DownloadConfigurationBuilder {
+        match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            (Ok(name), Ok(value)) => {
+                self.config.headers.append(name, value);
+            }
+            _ => {
+                self.invalid_header = Some(name.to_string());
+            }
+        }
+        self
+    }
+
+    /// Add headers sent with every request of this download.
+    pub fn set_headers(mut self, headers: HeaderMap) -> DownloadConfigurationBuilder {
+        self.config.headers.extend(headers);
+        self
+    }
+
+    /// Add a cookie sent with every request of this download.
+    pub fn set_cookie(mut self, name: &str, value: &str) -> DownloadConfigurationBuilder {
+        self.config.cookies.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set the `User-Agent` header for this download.
+    pub fn set_user_agent(mut self, user_agent: &str) -> DownloadConfigurationBuilder {
+        self.config.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Authenticate with HTTP Basic authentication.
+    pub fn set_basic_auth(mut self, username: &str, password: Option<&str>) -> DownloadConfigurationBuilder {
+        self.config.credentials = Some(Credentials::Basic {
+            username: username.to_string(),
+            password: password.map(str::to_string),
+        });
+        self
+    }
+
+    /// Authenticate with a bearer token.
+    pub fn set_bearer_auth(mut self, token: &str) -> DownloadConfigurationBuilder {
+        self.config.credentials = Some(Credentials::Bearer(token.to_string()));
+        self
+    }
+
+    /// Obtain credentials from `provider` before each request. Takes precedence over
+    /// [`set_basic_auth`](Self::set_basic_auth) and [`set_bearer_auth`](Self::set_bearer_auth).
+    pub fn set_credential_provider(mut self, provider: Arc<dyn CredentialProvider>) -> DownloadConfigurationBuilder {
+        self.config.credential_provider = Some(provider);
+        self
+    }
+
+    /// Use `proxy` for this download instead of the service's proxy settings.
+    /// The download then uses its own client, built from the service configuration.
+    pub fn set_proxy(mut self, proxy: ProxyConfiguration) -> DownloadConfigurationBuilder {
+        self.config.proxy = Some(proxy);
+        self
+    }
+
+    /// Obtain a fresh URL from `provider` when the server rejects the current one with
+    /// `401`/`403`, or once it is older than the [`set_url_expiry`](Self::set_url_expiry).
+    pub fn set_url_provider(mut self, provider: Arc<dyn UrlProvider>) -> DownloadConfigurationBuilder {
+        self.config.url_provider = Some(provider);
+        self
+    }
+
+    /// Set how many seconds a URL stays valid before the URL provider is asked for a
+    /// new one. 0 means URLs are only refreshed after being rejected.
+    pub fn set_url_expiry(mut self, url_expiry: u64) -> DownloadConfigurationBuilder {
+        self.config.url_expiry = url_expiry;
+        self
+    }
+
+    /// Set the maximum number of redirects followed per request. 0 disables redirects.
+    pub fn set_max_redirects(mut self, max_redirects: usize) -> DownloadConfigurationBuilder {
+        self.config.max_redirects = max_redirects;
+        self
+    }
+
     /// Set the file verification method (e.g., hash check).
     pub fn set_file_verify(mut self, file_verify: FileVerify) -> DownloadConfigurationBuilder {
         self.config.file_verify = file_verify;
         self
     }
 
+    /// Choose whether the file verification checks the compressed payload or the
+    /// decompressed file. Only relevant with [`set_decompression`](Self::set_decompression).
+    pub fn set_verify_target(mut self, verify_target: VerifyTarget) -> DownloadConfigurationBuilder {
+        self.config.verify_target = verify_target;
+        self
+    }
+
+    /// Decompress the downloaded payload into the destination. The payload is downloaded
+    /// as usual and decoded in a post-processing step, unless streaming decompression is enabled.
+    pub fn set_decompression(mut self, compression: Compression) -> DownloadConfigurationBuilder {
+        self.config.decompression = Some(compression);
+        self
+    }
+
+    /// Decode the payload while it is received instead of after the download. This uses a
+    /// single connection and cannot resume, and the compressed bytes are never stored, so
+    /// only the decompressed file can be verified.
+    pub fn set_streaming_decompression(mut self, streaming: bool) -> DownloadConfigurationBuilder {
+        self.config.streaming_decompression = streaming;
+        self
+    }
+
+    /// Extract the downloaded archive into `directory` once it is in place.
+    /// Progress is reported by [`DownloadOperation::extracted_size`](crate::download_operation::DownloadOperation::extracted_size).
+    pub fn set_extract_to(mut self, directory: impl AsRef<Path>) -> DownloadConfigurationBuilder {
+        self.config.extract_to = Some(directory.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the archive format. By default it is detected from the file extension.
+    pub fn set_archive_format(mut self, format: ArchiveFormat) -> DownloadConfigurationBuilder {
+        self.config.archive_format = Some(format);
+        self
+    }
+
+    /// Delete the archive after it has been extracted.
+    pub fn set_remove_archive(mut self, remove_archive: bool) -> DownloadConfigurationBuilder {
+        self.config.remove_archive = remove_archive;
+        self
+    }
+
+    /// Download a binary patch from the URL and apply it to the local `base` file. The
+    /// patched file is verified with the [`FileVerify`] before it replaces the destination,
+    /// which may be `base` itself.
+    pub fn set_patch(mut self, base: impl AsRef<Path>, format: PatchFormat) -> DownloadConfigurationBuilder {
+        self.config.patch_base = Some(base.as_ref().to_path_buf());
+        self.config.patch_format = Some(format);
+        self
+    }
+
+    /// Reuse the blocks of the older local file `base` that match the published `index`
+    /// of the new file, downloading only the other byte ranges. Falls back to a full
+    /// download if the server does not support ranges or its file does not match the index.
+    pub fn set_delta(mut self, base: impl AsRef<Path>, index: BlockIndex) -> DownloadConfigurationBuilder {
+        self.config.delta_base = Some(base.as_ref().to_path_buf());
+        self.config.delta_index = Some(Arc::new(index));
+        self
+    }
+
+    /// Write the download through sinks created by `factory` instead of to a file or
+    /// into memory. The data is not verified, cached or post-processed, and the
+    /// download is not journaled.
+    pub fn set_sink(mut self, factory: Arc<dyn SinkFactory>) -> DownloadConfigurationBuilder {
+        self.config.sink_factory = Some(factory);
+        self
+    }
+
+    /// Stream the body to the caller as it arrives instead of writing it to a file, see
+    /// [`DownloadOperation::body`](crate::download_operation::DownloadOperation::body).
+    /// The download waits while the reader falls behind. Like sink downloads, streamed
+    /// downloads are not verified, cached or journaled, and cannot be restarted once stopped.
+    pub fn set_stream_body(mut self, stream_body: bool) -> DownloadConfigurationBuilder {
+        self.config.stream_body = stream_body;
+        self
+    }
+
     /// Build the final [`DownloadConfiguration`], validating all required fields.
     pub fn build(self) -> crate::error::Result<DownloadConfiguration> {
         self.validate()
     }
 
     fn validate(self) -> crate::error::Result<DownloadConfiguration> {
+        if let Some(name) = self.invalid_header {
+            return Err(DownloadError::Config(format!("Invalid header: {}.", name)));
+        }
+
         if self.config.url.is_none() {
             return Err(DownloadError::Config("Download address not configured.".to_string()));
         }
 
-        if !self.config.download_in_memory {
-            if self.config.path.is_none() {
-                return Err(DownloadError::Config("No download path specified.".to_string()));
+        if self.config.sink_factory.is_some() || self.config.stream_body {
+            if self.config.download_in_memory || self.config.path.is_some() || self.config.directory.is_some()
+                || (self.config.sink_factory.is_some() && self.config.stream_body) {
+                return Err(DownloadError::Config("A download sink or body stream cannot be combined with another destination.".to_string()));
+            }
+            if self.config.decompression.is_some() || self.config.patch_format.is_some()
+                || self.config.delta_index.is_some() || self.config.extract_to.is_some() {
+                return Err(DownloadError::Config("Downloads to a sink or body stream cannot be decompressed, patched or extracted.".to_string()));
+            }
+        } else if !self.config.download_in_memory && self.config.path.is_none() && self.config.directory.is_none() {
+            return Err(DownloadError::Config("No download path specified.".to_string()));
+        }
+
+        if let Some(compression) = self.config.decompression {
+            if !compression.is_supported() {
+                return Err(DownloadError::Config(format!("{} support is not enabled, enable the `{}` feature.", compression, compression)));
+            }
+            if self.config.download_in_memory {
+                return Err(DownloadError::Config("Decompression is not supported for in-memory downloads.".to_string()));
+            }
+            if self.config.streaming_decompression
+                && self.config.file_verify != FileVerify::None
+                && self.config.verify_target == VerifyTarget::Compressed {
+                return Err(DownloadError::Config("Streaming decompression can only verify the decompressed file.".to_string()));
+            }
+        }
+
+        if let Some(format) = self.config.patch_format {
+            if !format.is_supported() {
+                return Err(DownloadError::Config(format!("{} patch support is not enabled.", format)));
+            }
+            if self.config.download_in_memory || self.config.decompression.is_some() {
+                return Err(DownloadError::Config("Patches cannot be downloaded in memory or decompressed.".to_string()));
+            }
+        }
+
+        if self.config.delta_index.is_some()
+            && (self.config.download_in_memory || self.config.decompression.is_some() || self.config.patch_format.is_some()) {
+            return Err(DownloadError::Config("Delta downloads cannot be in memory, decompressed or patched.".to_string()));
+        }
+
+        if self.config.extract_to.is_some() && self.config.download_in_memory {
+            return Err(DownloadError::Config("Extraction is not supported for in-memory downloads.".to_string()));
+        }
+
+        if let Some(format) = self.config.archive_format {
+            if !format.is_supported() {
+                return Err(DownloadError::Config(format!("{} archive support is not enabled.", format)));
             }
         }
 
@@ -125,12 +431,26 @@ impl DownloadConfigurationBuilder {
 
 impl DownloadConfiguration {
     /// Create a new [`DownloadConfigurationBuilder`].
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> DownloadConfigurationBuilder {
         let config = DownloadConfiguration {
             url: None,
             path: None,
             temp_path: None,
+            directory: None,
+            default_file_name: "download".to_string(),
+            existing_file_policy: ExistingFilePolicy::Overwrite,
             file_verify: FileVerify::None,
+            verify_target: VerifyTarget::Compressed,
+            decompression: None,
+            streaming_decompression: false,
+            extract_to: None,
+            archive_format: None,
+            remove_archive: false,
+            patch_base: None,
+            patch_format: None,
+            delta_base: None,
+            delta_index: None,
             range_download: true,
             chunk_download: false,
             chunk_size: 1024 * 1024 * 5,
@@ -138,22 +458,56 @@ impl DownloadConfiguration {
             remote_version: 0,
             retry_times_on_failure: 0,
             receive_bytes_per_second: 0,
+            receive_burst_bytes: 0,
             download_in_memory: false,
+            conditional_download: false,
             timeout: 0,
+            headers: HeaderMap::new(),
+            cookies: Vec::new(),
+            user_agent: None,
+            credentials: None,
+            credential_provider: None,
+            proxy: None,
+            url_provider: None,
+            sink_factory: None,
+            stream_body: false,
+            url_expiry: 0,
+            max_redirects: 10,
         };
         DownloadConfigurationBuilder::new(config)
     }
 
+    /// Copy this configuration with its destination set to `path`.
+    pub(crate) fn with_file_path(&self, path: impl AsRef<Path>) -> DownloadConfiguration {
+        let mut config = self.clone();
+        config.path = Some(path.as_ref().to_path_buf());
+        config.temp_path = Some(PathBuf::from(format!("{}.temp", path.as_ref().display())));
+        config
+    }
+
     /// Get the destination file path.
+    #[allow(clippy::needless_return)]
     pub fn get_file_path(&self) -> &Path {
         return self.path.as_ref().unwrap().as_path();
     }
 
+    /// Returns `true` if the download is written to a file rather than into memory, a sink or a body stream.
+    pub(crate) fn writes_file(&self) -> bool {
+        !self.download_in_memory && self.sink_factory.is_none() && !self.stream_body
+    }
+
     /// Get the temporary file path used during download.
+    #[allow(clippy::needless_return)]
     pub fn get_file_temp_path(&self) -> &Path {
         return self.temp_path.as_ref().unwrap().as_path();
     }
 
+    /// Returns `true` if the payload is decoded while it is received.
+    pub(crate) fn is_streaming_decompression(&self) -> bool {
+        self.decompression.is_some() && self.streaming_decompression
+    }
+
     /// Get the download URL.
+    #[allow(clippy::needless_return)]
     pub fn url(&self) -> &str { return self.url.as_ref().unwrap().as_str(); }
 }
\ No newline at end of file
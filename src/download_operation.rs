@@ -1,8 +1,13 @@
+use std::path::PathBuf;
 use std::sync::Arc;
+use bytes::Bytes;
+use crate::body_stream::BodyStream;
+use crate::download_outcome::DownloadOutcome;
 use crate::download_status::DownloadStatus;
 use crate::download_receiver::DownloadReceiver;
 use crate::downloader::Downloader;
 use crate::error::DownloadError;
+use crate::memory_budget::Payload;
 
 /// Handle to an active or completed download.
 ///
@@ -23,6 +28,7 @@ impl DownloadOperation {
     }
 
     /// Get the current download status.
+    #[allow(clippy::needless_return)]
     pub fn status(&self) -> DownloadStatus {
         let status = self.downloader.status();
         return status;
@@ -38,7 +44,13 @@ impl DownloadOperation {
         return *self.download_receiver.download_total_size_receiver.borrow();
     }
 
+    /// Get the number of bytes written so far by archive extraction.
+    pub fn extracted_size(&self) -> u64 {
+        self.download_receiver.extracted_size()
+    }
+
     /// Get the download progress as a value between 0.0 and 1.0.
+    #[allow(clippy::needless_return)]
     pub fn progress(&self) -> f64 {
         let total_size = self.total_size();
         if total_size == 0 {
@@ -49,18 +61,69 @@ impl DownloadOperation {
         return (downloaded_size / total_length).clamp(0f64, 1f64);
     }
 
-    /// Get the downloaded data (only available for in-memory downloads).
+    /// Get a copy of the downloaded data (only available for in-memory downloads).
     pub fn bytes(&self) -> Vec<u8> {
-        let bytes = self.download_receiver.memory_receiver.as_ref().unwrap().borrow();
-        bytes.to_vec()
+        self.payload_bytes().to_vec()
+    }
+
+    /// Get the downloaded data without copying it (only available for in-memory downloads).
+    /// The returned [`Bytes`] shares the buffer the download was written into; a payload
+    /// spilled to a file by the [`MemoryBudget`](crate::memory_budget::MemoryBudget) is read from it.
+    pub fn into_bytes(self) -> Bytes {
+        self.payload_bytes()
+    }
+
+    /// Get the result of an in-memory download, in memory or spilled to a file.
+    pub fn payload(&self) -> Payload {
+        self.download_receiver.memory_receiver.as_ref().unwrap().borrow().clone()
+    }
+
+    fn payload_bytes(&self) -> Bytes {
+        match self.payload().to_bytes() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to read spilled payload");
+                Bytes::new()
+            }
+        }
+    }
+
+    /// Take the body stream of a download configured with
+    /// [`set_stream_body`](crate::download_configuration::DownloadConfigurationBuilder::set_stream_body).
+    /// Returns `None` for other downloads and once the stream has been taken.
+    pub fn body(&self) -> Option<BodyStream> {
+        self.download_receiver.body.as_ref()?.lock().take()
+    }
+
+    /// Get how the file was obtained, once the download has completed successfully.
+    pub fn outcome(&self) -> Option<DownloadOutcome> {
+        *self.download_receiver.outcome_receiver.borrow()
+    }
+
+    /// Get the URL the download resolved to after following redirects, once probed.
+    pub fn resolved_url(&self) -> Option<String> {
+        self.download_receiver.resolved_url_receiver.borrow().clone()
+    }
+
+    /// Get the URLs redirected to while probing the download, in order.
+    pub fn redirect_chain(&self) -> Vec<String> {
+        self.download_receiver.redirect_chain_receiver.borrow().clone()
+    }
+
+    /// Get the destination file path. For downloads into a directory it is known once
+    /// the file name has been inferred.
+    pub fn file_path(&self) -> Option<PathBuf> {
+        self.download_receiver.file_path_receiver.borrow().clone()
     }
 
     /// Returns `true` if the download has completed (success or failure).
+    #[allow(clippy::needless_return)]
     pub fn is_done(&self) -> bool {
         return self.downloader.is_done();
     }
 
     /// Returns `true` if the download failed.
+    #[allow(clippy::needless_return)]
     pub fn is_error(&self) -> bool {
         return self.status() == DownloadStatus::Failed;
     }
@@ -74,4 +137,10 @@ impl DownloadOperation {
     pub fn stop(&self) {
         self.downloader.stop();
     }
+
+    /// Change the download's receive speed limit, 0 for unlimited, replacing the one it was
+    /// configured with. A running transfer slows down or speeds up without restarting.
+    pub fn set_speed_limit(&self, bytes_per_second: u64) {
+        self.downloader.set_speed_limit(bytes_per_second);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,26 @@
+use std::fmt::{Display, Formatter};
+
+/// How a completed download obtained its file.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DownloadOutcome {
+    /// The file was transferred from the server.
+    Downloaded,
+    /// The server answered `304 Not Modified`; the local file was kept.
+    UpToDate,
+    /// The file was placed from the [`DownloadCache`](crate::download_cache::DownloadCache).
+    Cached,
+    /// The destination already existed and the
+    /// [`ExistingFilePolicy`](crate::download_configuration::ExistingFilePolicy) kept it.
+    Skipped,
+}
+
+impl Display for DownloadOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadOutcome::Downloaded => write!(f, "Downloaded"),
+            DownloadOutcome::UpToDate => write!(f, "UpToDate"),
+            DownloadOutcome::Cached => write!(f, "Cached"),
+            DownloadOutcome::Skipped => write!(f, "Skipped"),
+        }
+    }
+}
@@ -1,14 +1,28 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::Mutex;
 use tokio::sync::watch::Receiver;
+use crate::body_stream::BodyStream;
+use crate::download_outcome::DownloadOutcome;
 use crate::error::DownloadError;
+use crate::memory_budget::Payload;
 
+#[derive(Clone)]
 pub struct DownloadReceiver {
     pub download_total_size_receiver: Receiver<u64>,
     pub error_receiver: Receiver<DownloadError>,
-    pub memory_receiver: Option<Receiver<Vec<u8>>>,
+    pub memory_receiver: Option<Receiver<Payload>>,
+    /// The body stream of a streamed download, until it is taken.
+    pub body: Option<Arc<Mutex<Option<BodyStream>>>>,
+    pub outcome_receiver: Receiver<Option<DownloadOutcome>>,
+    pub resolved_url_receiver: Receiver<Option<String>>,
+    pub redirect_chain_receiver: Receiver<Vec<String>>,
+    pub file_path_receiver: Receiver<Option<PathBuf>>,
     /// Shared counter for total downloaded bytes — same Arc as in DownloadSender.
     pub downloaded_size: Arc<AtomicU64>,
+    /// Bytes written by archive extraction — same Arc as in DownloadSender.
+    pub extracted_size: Arc<AtomicU64>,
 }
 
 impl DownloadReceiver {
@@ -16,4 +30,9 @@ impl DownloadReceiver {
     pub fn downloaded_size(&self) -> u64 {
         self.downloaded_size.load(Ordering::Relaxed)
     }
+
+    /// Returns the number of bytes extracted from the archive.
+    pub fn extracted_size(&self) -> u64 {
+        self.extracted_size.load(Ordering::Relaxed)
+    }
 }
\ No newline at end of file
@@ -1,12 +1,38 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
+use parking_lot::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::watch::Sender;
+use crate::body_stream::BodyPart;
+use crate::download_outcome::DownloadOutcome;
 use crate::error::DownloadError;
+use crate::memory_budget::Payload;
 
 pub struct DownloadSender {
     pub download_total_size_sender: Sender<u64>,
     pub error_sender: Sender<DownloadError>,
-    pub memory_sender: Option<Sender<Vec<u8>>>,
+    pub memory_sender: Option<Sender<Payload>>,
+    /// Feeds the body stream of a streamed download until the download ends.
+    pub(crate) body_sender: Mutex<Option<UnboundedSender<BodyPart>>>,
+    pub outcome_sender: Sender<Option<DownloadOutcome>>,
+    pub resolved_url_sender: Sender<Option<String>>,
+    pub redirect_chain_sender: Sender<Vec<String>>,
+    pub file_path_sender: Sender<Option<PathBuf>>,
     /// Shared counter for total downloaded bytes across all chunks.
     pub downloaded_size: Arc<AtomicU64>,
-}
\ No newline at end of file
+    /// Bytes written by archive extraction so far.
+    pub extracted_size: Arc<AtomicU64>,
+}
+
+impl DownloadSender {
+    /// End the body stream, completing it if the download succeeded. Later runs of the
+    /// download cannot stream again.
+    pub fn finish_body(&self, complete: bool) {
+        if let Some(body_sender) = self.body_sender.lock().take() {
+            if complete {
+                let _ = body_sender.send(BodyPart::End);
+            }
+        }
+    }
+}
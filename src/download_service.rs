@@ -1,18 +1,44 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use reqwest::{Client, ClientBuilder};
+use reqwest::{redirect, Client, Url};
 use parking_lot::RwLock;
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 use tokio_util::sync::CancellationToken;
+use crate::download_cache::DownloadCache;
+use crate::memory_budget::MemoryBudget;
+use crate::rate_limiter::RateLimiter;
+use crate::bandwidth_schedule::{Bandwidth, BandwidthSchedule};
 use crate::download_configuration::DownloadConfiguration;
 use crate::download_operation::DownloadOperation;
+use crate::download_receiver::DownloadReceiver;
+use crate::download_status::DownloadStatus;
 use crate::download_tracker;
 use crate::downloader::Downloader;
+use crate::error::DownloadError;
+use crate::queue_store::QueueStore;
+use crate::service_configuration::{ProxyConfiguration, ServiceConfiguration};
 use tracing;
 
 type DownloaderQueue = VecDeque<Arc<Downloader>>;
 
+/// Identifies downloads that would write to the same target: the URL plus the
+/// destination path or directory, or no path for in-memory downloads.
+type DownloadKey = (String, Option<PathBuf>);
+
+/// What [`DownloadService::add_downloader`] does when the same URL is already being
+/// downloaded to the same destination.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DuplicatePolicy {
+    /// Return another handle to the download that is already queued or running.
+    /// In-memory downloads share one fetch among all handles.
+    Attach,
+    /// Return a handle that fails with [`DownloadError::Duplicate`].
+    Reject,
+}
+
 /// Service that manages concurrent downloads with configurable parallelism.
 ///
 /// Call [`run()`](DownloadService::run) within your Tokio runtime to start the scheduling loop.
@@ -20,21 +46,115 @@ pub struct DownloadService {
     cancel_token: CancellationToken,
     parallel_count: Arc<RwLock<usize>>,
     download_queue: Arc<RwLock<DownloaderQueue>>,
+    active_downloads: Arc<RwLock<Vec<Arc<Downloader>>>>,
+    accepting: AtomicBool,
+    queue_store: RwLock<Option<Arc<QueueStore>>>,
+    in_flight: RwLock<HashMap<DownloadKey, (Arc<Downloader>, DownloadReceiver)>>,
+    duplicate_policy: RwLock<DuplicatePolicy>,
+    cache: RwLock<Option<Arc<DownloadCache>>>,
+    memory_budget: RwLock<Option<Arc<MemoryBudget>>>,
+    /// Shared by every download.
+    rate_limiter: Arc<RateLimiter>,
+    /// Shared by the downloads from each host, keyed by lowercase host name. Every host a
+    /// download is added for gets one, unlimited until a limit is set, so a limit set later
+    /// reaches the downloads already queued or running.
+    host_rate_limiters: RwLock<HashMap<String, Arc<RateLimiter>>>,
+    bandwidth_schedule: RwLock<Option<Arc<BandwidthSchedule>>>,
+    /// The bandwidth last applied from the schedule.
+    scheduled_bandwidth: RwLock<Option<Bandwidth>>,
     client: Arc<Client>,
+    /// Settings the shared client was built from, used to build clients for downloads
+    /// with their own proxy. `None` when the client was supplied by the caller.
+    configuration: Option<ServiceConfiguration>,
+    proxy_clients: RwLock<HashMap<ProxyConfiguration, Arc<Client>>>,
+}
+
+/// A download that was interrupted by [`DownloadService::shutdown`].
+pub struct InterruptedDownload {
+    pub url: String,
+    pub file_path: Option<PathBuf>,
+    pub downloaded_size: u64,
+    pub total_size: u64,
+    /// `true` if the partial data was flushed to disk and a later download
+    /// with the same configuration will continue from it.
+    pub resumable: bool,
+}
+
+/// Result of [`DownloadService::shutdown`].
+pub struct ShutdownReport {
+    /// Downloads that were running when the service shut down.
+    pub interrupted: Vec<InterruptedDownload>,
+    /// Downloads that were still waiting in the queue.
+    pub queued: Vec<InterruptedDownload>,
+    /// `true` if some downloads did not stop before the timeout elapsed.
+    pub timed_out: bool,
+}
+
+impl Default for DownloadService {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DownloadService {
     /// Create a new download service with default settings.
+    ///
+    /// If the default client cannot be built, the service falls back to a client with
+    /// reqwest's default TLS backend that, like every configured client, does not follow
+    /// redirects itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the fallback client cannot be built either, such as when TLS cannot be
+    /// initialized. Use [`with_configuration`](DownloadService::with_configuration) to
+    /// handle that error instead.
     pub fn new() -> Self {
-        let client = ClientBuilder::new()
-            .use_rustls_tls()
-            .build()
-            .unwrap();
+        match Self::with_configuration(ServiceConfiguration::default()) {
+            Ok(service) => service,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to build configured client, using reqwest defaults");
+                let client = Client::builder()
+                    .redirect(redirect::Policy::none())
+                    .build()
+                    .expect("failed to build an HTTP client");
+                Self::with_client(client)
+            }
+        }
+    }
+
+    /// Create a download service whose client is built from `configuration`.
+    pub fn with_configuration(configuration: ServiceConfiguration) -> crate::error::Result<Self> {
+        let client = configuration.build_client()?;
+        let mut service = Self::with_client(client);
+        service.set_parallel_count(configuration.parallel_count);
+        service.configuration = Some(configuration);
+        Ok(service)
+    }
+
+    /// Create a download service that sends all requests through `client`.
+    ///
+    /// Build `client` with [`redirect::Policy::none`]: downloads follow redirects
+    /// themselves, so a client that follows them bypasses the redirect limit, the reported
+    /// redirect chain and the removal of credentials on redirects to other hosts.
+    pub fn with_client(client: Client) -> Self {
         Self {
             download_queue: Arc::new(RwLock::new(DownloaderQueue::new())),
+            active_downloads: Arc::new(RwLock::new(Vec::new())),
             parallel_count: Arc::new(RwLock::new(32)),
+            accepting: AtomicBool::new(true),
+            queue_store: RwLock::new(None),
+            in_flight: RwLock::new(HashMap::new()),
+            duplicate_policy: RwLock::new(DuplicatePolicy::Attach),
+            cache: RwLock::new(None),
+            memory_budget: RwLock::new(None),
+            rate_limiter: RateLimiter::new(0),
+            host_rate_limiters: RwLock::new(HashMap::new()),
+            bandwidth_schedule: RwLock::new(None),
+            scheduled_bandwidth: RwLock::new(None),
             cancel_token: CancellationToken::new(),
             client: Arc::new(client),
+            configuration: None,
+            proxy_clients: RwLock::new(HashMap::new()),
         }
     }
 
@@ -45,50 +165,80 @@ impl DownloadService {
     /// before any `.await` point, making this future `Send`.
     pub async fn run(&self) {
         tracing::info!("download service started");
-        let mut downloadings: Vec<Arc<Downloader>> = Vec::new();
 
         loop {
             if self.cancel_token.is_cancelled() {
                 break;
             }
 
+            // A paused schedule window lets no download run.
+            let paused = self.apply_bandwidth_schedule() == Some(Bandwidth::Paused);
+
             // Read parallel limit and queue length with guards dropped immediately
-            let parallel_limit = { *self.parallel_count.read() };
-            let mut queue_has_items = { self.download_queue.read().len() > 0 };
+            let parallel_limit = if paused { 0 } else { *self.parallel_count.read() };
+            let mut queue_has_items = { !self.download_queue.read().is_empty() };
 
             // Start new downloads up to parallel limit
-            while downloadings.len() < parallel_limit && queue_has_items {
-                let next = { self.download_queue.write().pop_front() };
-                match next {
-                    Some(downloader) => {
-                        if !downloader.is_pending_async().await {
-                            queue_has_items = self.download_queue.read().len() > 0;
-                            continue;
-                        }
-                        downloadings.push(downloader.clone());
-                        tracing::debug!(active = downloadings.len(), "starting download task");
+            while self.active_downloads.read().len() < parallel_limit && queue_has_items {
+                // Popped and started under the lock `shutdown` drains with, so every download
+                // is either running or still queued when it is drained.
+                let skipped = {
+                    let mut active_downloads = self.active_downloads.write();
+                    if self.cancel_token.is_cancelled() {
+                        break;
+                    }
+                    let Some(downloader) = self.download_queue.write().pop_front() else {
+                        break;
+                    };
+                    if downloader.status() == DownloadStatus::Pending {
+                        active_downloads.push(downloader.clone());
+                        tracing::debug!(active = active_downloads.len(), "starting download task");
                         downloader.start_download();
+                        None
+                    } else {
+                        Some(downloader)
                     }
-                    None => break,
+                };
+                if let Some(downloader) = skipped {
+                    self.forget_journaled(&downloader);
                 }
-                queue_has_items = self.download_queue.read().len() > 0;
+                queue_has_items = !self.download_queue.read().is_empty();
             }
 
             // Remove completed downloads
-            downloadings.retain(|d| !d.is_done());
-
-            // Handle parallel count reduction
-            let current_parallel = { *self.parallel_count.read() };
-            if downloadings.len() > current_parallel {
-                let mut remove_count = downloadings.len() - current_parallel;
-                while remove_count > 0 && !downloadings.is_empty() {
-                    let index = downloadings.len() - 1;
-                    let downloader = downloadings[index].clone();
-                    downloader.stop_async().await;
-                    downloader.pending_async().await;
-                    { self.download_queue.write().push_back(downloader); }
-                    downloadings.remove(index);
-                    remove_count -= 1;
+            let finished: Vec<Arc<Downloader>> = {
+                let mut active_downloads = self.active_downloads.write();
+                let (finished, running) = active_downloads.drain(..).partition(|d| d.is_done());
+                *active_downloads = running;
+                finished
+            };
+            // Downloads stopped by a shutdown stay journaled so they can be resumed.
+            if self.accepting.load(Ordering::Acquire) {
+                for downloader in &finished {
+                    self.forget_journaled(downloader);
+                }
+            }
+            if !finished.is_empty() {
+                self.in_flight.write().retain(|_, (d, _)| !d.is_done());
+            }
+
+            // Handle parallel count reduction, or a pause, by requeueing running downloads.
+            // A streamed body cannot be restarted, so those keep running; the paused service
+            // limiter holds them at zero speed instead.
+            let current_parallel = if paused { 0 } else { *self.parallel_count.read() };
+            {
+                let mut active_downloads = self.active_downloads.write();
+                let mut excess = active_downloads.len().saturating_sub(current_parallel);
+                let mut index = active_downloads.len();
+                while excess > 0 && index > 0 && !self.cancel_token.is_cancelled() {
+                    index -= 1;
+                    if active_downloads[index].config().stream_body {
+                        continue;
+                    }
+                    let downloader = active_downloads.remove(index);
+                    downloader.requeue();
+                    self.download_queue.write().push_back(downloader);
+                    excess -= 1;
                 }
             }
 
@@ -104,31 +254,327 @@ impl DownloadService {
         *self.parallel_count.write() = parallel_count;
     }
 
+    /// Set how downloads of a URL that is already queued or running for the same
+    /// destination are handled. Defaults to [`DuplicatePolicy::Attach`].
+    pub fn set_duplicate_policy(&self, duplicate_policy: DuplicatePolicy) {
+        *self.duplicate_policy.write() = duplicate_policy;
+    }
+
+    /// Serve file downloads from, and store them in, a shared [`DownloadCache`].
+    /// Applies to downloads added afterwards.
+    pub fn set_cache(&self, cache: DownloadCache) {
+        *self.cache.write() = Some(Arc::new(cache));
+    }
+
+    /// Limit the memory held by in-memory downloads to a shared [`MemoryBudget`].
+    /// Applies to downloads added afterwards.
+    pub fn set_memory_budget(&self, memory_budget: MemoryBudget) {
+        *self.memory_budget.write() = Some(Arc::new(memory_budget));
+    }
+
+    /// Limit the combined receive speed of all downloads to `bytes_per_second`, 0 for
+    /// unlimited. Downloads share it fairly, on top of their own
+    /// [`set_download_speed_limit`](crate::download_configuration::DownloadConfigurationBuilder::set_download_speed_limit)
+    /// and their host's limit. Running downloads pick up the change without restarting.
+    pub fn set_speed_limit(&self, bytes_per_second: u64) {
+        self.rate_limiter.set_bytes_per_second(bytes_per_second);
+    }
+
+    /// Set how many bytes all downloads together may receive at once after a pause, above
+    /// the service-wide speed limit. 0, the default, allows one second's worth.
+    pub fn set_speed_burst(&self, burst: u64) {
+        self.rate_limiter.set_burst(burst);
+    }
+
+    /// Limit the combined receive speed of the downloads from `host` to `bytes_per_second`,
+    /// 0 for unlimited. Downloads already queued or running pick up the change without
+    /// restarting.
+    pub fn set_host_speed_limit(&self, host: &str, bytes_per_second: u64) {
+        self.host_rate_limiter(&host.to_ascii_lowercase()).set_bytes_per_second(bytes_per_second);
+    }
+
+    /// Drive the service-wide speed limit from `schedule` and pause downloads during its
+    /// [`Bandwidth::Paused`] windows. The scheduled bandwidth replaces the limit set with
+    /// [`set_speed_limit`](DownloadService::set_speed_limit) whenever it changes.
+    pub fn set_bandwidth_schedule(&self, schedule: BandwidthSchedule) {
+        *self.bandwidth_schedule.write() = Some(Arc::new(schedule));
+        *self.scheduled_bandwidth.write() = None;
+    }
+
+    /// Stop following the bandwidth schedule, leaving the service-wide speed limit as the
+    /// schedule last set it.
+    pub fn clear_bandwidth_schedule(&self) {
+        *self.bandwidth_schedule.write() = None;
+        *self.scheduled_bandwidth.write() = None;
+        self.rate_limiter.set_paused(false);
+    }
+
+    /// Apply the schedule's current bandwidth if it changed, and return it.
+    fn apply_bandwidth_schedule(&self) -> Option<Bandwidth> {
+        let schedule = { self.bandwidth_schedule.read().clone() }?;
+        let bandwidth = schedule.bandwidth();
+        let mut scheduled_bandwidth = self.scheduled_bandwidth.write();
+        if *scheduled_bandwidth != Some(bandwidth) {
+            tracing::info!(?bandwidth, "applying bandwidth schedule");
+            match bandwidth {
+                Bandwidth::Unlimited => self.rate_limiter.set_bytes_per_second(0),
+                Bandwidth::Limited(bytes_per_second) => self.rate_limiter.set_bytes_per_second(bytes_per_second),
+                Bandwidth::Paused => {}
+            }
+            self.rate_limiter.set_paused(bandwidth == Bandwidth::Paused);
+            *scheduled_bandwidth = Some(bandwidth);
+        }
+        Some(bandwidth)
+    }
+
+    /// The combined receive speed limit of all downloads, 0 if unlimited.
+    pub fn speed_limit(&self) -> u64 {
+        self.rate_limiter.bytes_per_second()
+    }
+
     /// Add a download to the queue and return a handle to monitor it.
-    pub fn add_downloader(&mut self, config: DownloadConfiguration) -> DownloadOperation {
-        let (tx, rx) = download_tracker::new(config.download_in_memory);
-        let mut downloader = Downloader::new(config, self.client.clone(), Arc::new(tx));
+    ///
+    /// If the same URL is already queued or running for the same destination, the
+    /// [`DuplicatePolicy`] decides whether the returned handle is attached to that
+    /// download or fails with [`DownloadError::Duplicate`]. Stopping an attached
+    /// handle stops the shared download.
+    ///
+    /// After [`shutdown`](DownloadService::shutdown) has been called the download
+    /// is rejected and the returned operation fails with [`DownloadError::ServiceShutdown`].
+    pub fn add_downloader(&self, config: DownloadConfiguration) -> DownloadOperation {
+        if !self.accepting.load(Ordering::Acquire) {
+            return self.rejected(config, DownloadError::ServiceShutdown);
+        }
+
+        let key = duplicate_key(&config);
+        let existing = key.as_ref().and_then(|key| {
+            self.in_flight.read().get(key)
+                .filter(|(d, _)| !d.is_done())
+                .map(|(d, rx)| (d.clone(), rx.clone()))
+        });
+        if let (Some((downloader, rx)), Some(key)) = (existing, key) {
+            let duplicate_policy = { *self.duplicate_policy.read() };
+            tracing::debug!(url = config.url(), ?duplicate_policy, "duplicate download");
+            return match duplicate_policy {
+                DuplicatePolicy::Attach => DownloadOperation::new(downloader, rx),
+                DuplicatePolicy::Reject => {
+                    let message = match &key.1 {
+                        Some(path) => format!("{} is already being downloaded to {}", key.0, path.display()),
+                        None => format!("{} is already being downloaded into memory", key.0),
+                    };
+                    self.rejected(config, DownloadError::Duplicate(message))
+                }
+            };
+        }
+
+        let journal_id = match (self.queue_store.read().as_ref(), config.writes_file()) {
+            (Some(store), true) => match store.add(&config) {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed to journal download");
+                    None
+                }
+            },
+            _ => None,
+        };
+        self.enqueue(config, journal_id)
+    }
+
+    /// Open a file-backed queue journal at `path` and enqueue every download recorded in it.
+    ///
+    /// Downloads added afterwards are journaled until they complete, fail or are stopped,
+    /// so a service created after a restart can pick them up again by loading the same
+    /// journal. Partially downloaded files resume from their chunk files and `.metadata`.
+    /// Credentials and cookies are not journaled.
+    /// Returns a handle for each restored download.
+    pub fn load_queue_store(&self, path: impl AsRef<Path>) -> crate::error::Result<Vec<DownloadOperation>> {
+        let (store, downloads) = QueueStore::open(path)?;
+        *self.queue_store.write() = Some(Arc::new(store));
+        tracing::info!(restored = downloads.len(), "loaded download queue journal");
+
+        let operations = downloads.into_iter()
+            .map(|(id, config)| self.enqueue(config, Some(id)))
+            .collect();
+        Ok(operations)
+    }
+
+    fn enqueue(&self, config: DownloadConfiguration, journal_id: Option<u64>) -> DownloadOperation {
+        let client = match &config.proxy {
+            Some(proxy) => match self.proxy_client(proxy) {
+                Ok(client) => client,
+                Err(e) => return self.rejected(config, e),
+            },
+            None => self.client.clone(),
+        };
+        let (tx, rx) = download_tracker::new(config.download_in_memory, config.stream_body);
+        let mut downloader = Downloader::new(config, client, Arc::new(tx));
+        if let Some(id) = journal_id {
+            downloader.set_journal_id(id);
+        }
+        if let Some(cache) = self.cache.read().as_ref() {
+            downloader.set_cache(cache.clone());
+        }
+        if let Some(memory_budget) = self.memory_budget.read().as_ref() {
+            downloader.set_memory_budget(memory_budget.clone());
+        }
+        downloader.set_shared_rate_limiters(self.shared_rate_limiters(downloader.config()));
         downloader.pending();
         let downloader = Arc::new(downloader);
+        if let Some(key) = duplicate_key(downloader.config()) {
+            self.in_flight.write().insert(key, (downloader.clone(), rx.clone()));
+        }
         self.download_queue.write().push_back(downloader.clone());
-        let operation = DownloadOperation::new(downloader.clone(), rx);
-        return operation;
+        DownloadOperation::new(downloader.clone(), rx)
+    }
+
+    /// The limiters shared by a download: its host's, if it has one, and the service's.
+    fn shared_rate_limiters(&self, config: &DownloadConfiguration) -> Vec<Arc<RateLimiter>> {
+        let mut limiters = Vec::new();
+        let host = Url::parse(config.url()).ok()
+            .and_then(|url| url.host_str().map(|host| host.to_ascii_lowercase()));
+        if let Some(host) = host {
+            limiters.push(self.host_rate_limiter(&host));
+        }
+        limiters.push(self.rate_limiter.clone());
+        limiters
+    }
+
+    /// The limiter shared by the downloads from `host`, created unlimited on first use.
+    fn host_rate_limiter(&self, host: &str) -> Arc<RateLimiter> {
+        if let Some(limiter) = self.host_rate_limiters.read().get(host) {
+            return limiter.clone();
+        }
+        self.host_rate_limiters.write().entry(host.to_string())
+            .or_insert_with(|| RateLimiter::new(0))
+            .clone()
+    }
+
+    /// Get the client for downloads using `proxy`, building it on first use so downloads
+    /// through the same proxy share a connection pool.
+    fn proxy_client(&self, proxy: &ProxyConfiguration) -> crate::error::Result<Arc<Client>> {
+        if let Some(client) = self.proxy_clients.read().get(proxy) {
+            return Ok(client.clone());
+        }
+        let mut configuration = self.configuration.clone().unwrap_or_default();
+        configuration.proxy = Some(proxy.clone());
+        let client = Arc::new(configuration.build_client()?);
+        self.proxy_clients.write().insert(proxy.clone(), client.clone());
+        Ok(client)
+    }
+
+    fn rejected(&self, config: DownloadConfiguration, error: DownloadError) -> DownloadOperation {
+        let (tx, rx) = download_tracker::new(config.download_in_memory, false);
+        let mut downloader = Downloader::new(config, self.client.clone(), Arc::new(tx));
+        downloader.fail(error);
+        DownloadOperation::new(Arc::new(downloader), rx)
+    }
+
+    fn forget_journaled(&self, downloader: &Downloader) {
+        let Some(id) = downloader.journal_id() else {
+            return;
+        };
+        if let Some(store) = self.queue_store.read().as_ref() {
+            if let Err(e) = store.remove(id) {
+                tracing::warn!(id, error = %e, "failed to remove download from journal");
+            }
+        }
     }
 
     pub fn stop(&self) {
         tracing::info!("stopping download service");
         self.cancel_token.cancel();
     }
+
+    /// Gracefully shut the service down.
+    ///
+    /// Stops accepting new downloads, ends the scheduling loop and cancels every
+    /// active download. Each chunk flushes its file before its task exits, so the
+    /// chunk files and `.metadata` left on disk can be resumed by a later download
+    /// with the same configuration. Waits at most `timeout` for active downloads to stop.
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        tracing::info!("shutting down download service");
+        self.accepting.store(false, Ordering::Release);
+        self.cancel_token.cancel();
+
+        // The run loop moves downloads between the queue and the active list only while
+        // holding the active list's lock, so none is missed.
+        let (active, queued): (Vec<Arc<Downloader>>, Vec<Arc<Downloader>>) = {
+            let mut active_downloads = self.active_downloads.write();
+            let queued = self.download_queue.write().drain(..).collect();
+            (active_downloads.drain(..).collect(), queued)
+        };
+        self.in_flight.write().clear();
+
+        for downloader in &active {
+            if !downloader.is_done() {
+                downloader.stop_async().await;
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut timed_out = false;
+        while active.iter().any(|d| !d.is_done()) {
+            if Instant::now() >= deadline {
+                timed_out = true;
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let interrupted: Vec<InterruptedDownload> = active.iter()
+            .filter(|d| d.status() != DownloadStatus::Complete)
+            .map(|d| interrupted_download(d))
+            .collect();
+        let queued: Vec<InterruptedDownload> = queued.iter()
+            .map(|d| interrupted_download(d))
+            .collect();
+
+        if timed_out {
+            tracing::warn!(interrupted = interrupted.len(), "shutdown timed out before all downloads stopped");
+        }
+        tracing::info!(interrupted = interrupted.len(), queued = queued.len(), "download service shut down");
+
+        ShutdownReport {
+            interrupted,
+            queued,
+            timed_out,
+        }
+    }
+}
+
+/// Downloads to a sink or body stream have no key, since each is a target of its own.
+fn duplicate_key(config: &DownloadConfiguration) -> Option<DownloadKey> {
+    if config.sink_factory.is_some() || config.stream_body {
+        return None;
+    }
+    let path = match config.download_in_memory {
+        true => None,
+        false => config.path.clone().or_else(|| config.directory.clone()),
+    };
+    Some((config.url().to_string(), path))
+}
+
+fn interrupted_download(downloader: &Downloader) -> InterruptedDownload {
+    let config = downloader.config();
+    InterruptedDownload {
+        url: config.url().to_string(),
+        file_path: downloader.file_path(),
+        downloaded_size: downloader.downloaded_size(),
+        total_size: downloader.total_size(),
+        resumable: downloader.is_done() && downloader.is_resumable(),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::download_configuration::DownloadConfiguration;
-    use crate::download_service::DownloadService;
+    use crate::download_service::{DownloadService, DuplicatePolicy};
+    use crate::download_status::DownloadStatus;
+    use crate::error::DownloadError;
 
     #[tokio::test]
     pub async fn test_download_service() {
-        let mut service = DownloadService::new();
+        let service = DownloadService::new();
         let url = "https://lan.sausage.xd.com/servers.txt".to_string();
         let config = DownloadConfiguration::new()
             .set_url(&url)
@@ -159,4 +605,401 @@ mod test {
         // The service handle will be dropped and cancelled
         service_handle.abort();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    pub async fn test_shutdown_rejects_new_downloads() {
+        let service = DownloadService::new();
+        let config = DownloadConfiguration::new()
+            .set_url("https://example.com/queued.bin")
+            .set_file_path("./downloads/queued.bin")
+            .build()
+            .unwrap();
+        service.add_downloader(config);
+
+        let report = service.shutdown(std::time::Duration::from_secs(1)).await;
+        assert!(report.interrupted.is_empty());
+        assert_eq!(report.queued.len(), 1);
+        assert!(!report.timed_out);
+
+        let config = DownloadConfiguration::new()
+            .set_url("https://example.com/late.bin")
+            .set_download_in_memory(true)
+            .build()
+            .unwrap();
+        let operation = service.add_downloader(config);
+        assert!(operation.is_done());
+        assert!(operation.is_error());
+    }
+
+    #[tokio::test]
+    pub async fn test_shutdown_keeps_active_download_resumable() {
+        use std::sync::Arc;
+        use parking_lot::Mutex;
+        use crate::chunk_hub::chunk_file_path;
+        use crate::sink::tests::serve_validated;
+
+        let root = std::env::temp_dir().join(format!("downloader-rs-shutdown-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let body: Arc<Vec<u8>> = Arc::new((0..1024 * 1024u32).map(|i| (i % 249) as u8).collect());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let url = serve_validated(body.clone(), "\"v1\"", "Mon, 01 Jan 2024 00:00:00 GMT", requests.clone()).await;
+        let path = root.join("file.bin");
+        let config = |bytes_per_second| DownloadConfiguration::new()
+            .set_url(&url)
+            .set_file_path(&path)
+            .set_chunk_download(true)
+            .set_chunk_size(256 * 1024)
+            .set_download_speed_limit(bytes_per_second)
+            .build()
+            .unwrap();
+
+        let service = Arc::new(DownloadService::new());
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+        let operation = service.add_downloader(config(512 * 1024));
+        while operation.downloaded_size() < 600 * 1024 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let report = service.shutdown(std::time::Duration::from_secs(5)).await;
+        handle.abort();
+        assert!(!report.timed_out);
+        assert_eq!(report.interrupted.len(), 1);
+        let interrupted = &report.interrupted[0];
+        assert!(interrupted.resumable);
+        assert_eq!(interrupted.file_path.as_deref(), Some(path.as_path()));
+        let downloaded = interrupted.downloaded_size;
+        assert!(downloaded < body.len() as u64);
+        assert!(std::fs::metadata(root.join("file.bin.metadata")).is_ok());
+        let kept: u64 = (0..4)
+            .map(|i| std::fs::metadata(chunk_file_path(&path, i)).unwrap().len())
+            .sum();
+        assert_eq!(kept, downloaded);
+
+        // A new service only requests what the chunk files do not hold yet.
+        requests.lock().clear();
+        let service = Arc::new(DownloadService::new());
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+        let operation = service.add_downloader(config(0));
+        while !operation.is_done() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        handle.abort();
+        assert_eq!(operation.status(), DownloadStatus::Complete);
+        assert_eq!(std::fs::read(&path).unwrap(), *body);
+        let requested: u64 = requests.lock().iter()
+            .filter_map(|head| head.lines().find_map(|line| line.strip_prefix("range: bytes=")))
+            .map(|range| {
+                let (start, end) = range.split_once('-').unwrap();
+                end.parse::<u64>().unwrap() - start.parse::<u64>().unwrap() + 1
+            })
+            .sum();
+        assert_eq!(requested, body.len() as u64 - downloaded);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    pub async fn test_duplicate_downloads() {
+        let service = DownloadService::new();
+        let config = || DownloadConfiguration::new()
+            .set_url("https://example.com/shared.bin")
+            .set_file_path("./downloads/shared.bin")
+            .build()
+            .unwrap();
+
+        let first = service.add_downloader(config());
+        let attached = service.add_downloader(config());
+        assert_eq!(attached.status(), DownloadStatus::Pending);
+        first.stop();
+        assert_eq!(attached.status(), DownloadStatus::Stop);
+
+        let other = service.add_downloader(DownloadConfiguration::new()
+            .set_url("https://example.com/shared.bin")
+            .set_file_path("./downloads/other.bin")
+            .build()
+            .unwrap());
+        assert_eq!(other.status(), DownloadStatus::Pending);
+
+        service.set_duplicate_policy(DuplicatePolicy::Reject);
+        let rejected = service.add_downloader(DownloadConfiguration::new()
+            .set_url("https://example.com/shared.bin")
+            .set_file_path("./downloads/other.bin")
+            .build()
+            .unwrap());
+        assert!(rejected.is_error());
+        assert!(matches!(rejected.error(), DownloadError::Duplicate(_)));
+    }
+
+    #[tokio::test]
+    pub async fn test_duplicate_of_requeued_download() {
+        use std::sync::Arc;
+        use chrono::Weekday;
+        use parking_lot::Mutex;
+        use crate::bandwidth_schedule::{Bandwidth, BandwidthSchedule};
+        use crate::bandwidth_schedule::tests::{at, hm, ManualClock};
+        use crate::sink::tests::serve;
+
+        let url = serve(Arc::new(vec![9u8; 1024 * 1024])).await;
+        let clock = Arc::new(ManualClock(Mutex::new(at(1, 8, 0))));
+        let schedule = BandwidthSchedule::new(Bandwidth::Limited(256 * 1024))
+            .add_window(&[Weekday::Mon], hm(9, 0), hm(17, 0), Bandwidth::Paused)
+            .set_clock(clock.clone());
+        let service = Arc::new(DownloadService::new());
+        service.set_bandwidth_schedule(schedule);
+        service.set_duplicate_policy(DuplicatePolicy::Reject);
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let config = || DownloadConfiguration::new()
+            .set_url(&url)
+            .set_download_in_memory(true)
+            .build()
+            .unwrap();
+        let operation = service.add_downloader(config());
+        while operation.downloaded_size() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // The paused window requeues the download; it is still the one in flight.
+        *clock.0.lock() = at(1, 10, 0);
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(operation.status(), DownloadStatus::Pending);
+        assert!(!operation.is_done());
+        let duplicate = service.add_downloader(config());
+        assert!(matches!(duplicate.error(), DownloadError::Duplicate(_)));
+
+        operation.stop();
+        handle.abort();
+    }
+
+    #[tokio::test]
+    pub async fn test_conditional_download() {
+        use std::sync::Arc;
+        use parking_lot::Mutex;
+        use crate::download_outcome::DownloadOutcome;
+        use crate::sink::tests::serve_validated;
+
+        let root = std::env::temp_dir().join(format!("downloader-rs-conditional-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let last_modified = "Mon, 01 Jan 2024 00:00:00 GMT";
+        let url = serve_validated(Arc::new(vec![1u8; 10_000]), "\"v1\"", last_modified, requests.clone()).await;
+        let service = Arc::new(DownloadService::new());
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let path = root.join("file.bin");
+        let download = || async {
+            let config = DownloadConfiguration::new()
+                .set_url(&url)
+                .set_file_path(&path)
+                .set_conditional_download(true)
+                .build()
+                .unwrap();
+            let operation = service.add_downloader(config);
+            while !operation.is_done() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            operation.outcome()
+        };
+
+        assert_eq!(download().await, Some(DownloadOutcome::Downloaded));
+        let validators = std::fs::read_to_string(root.join("file.bin.validators")).unwrap();
+        assert_eq!(validators, format!("\"v1\"\n{}\n", last_modified));
+
+        // The validators are sent back and the 304 leaves the destination alone.
+        std::fs::write(&path, b"local").unwrap();
+        requests.lock().clear();
+        assert_eq!(download().await, Some(DownloadOutcome::UpToDate));
+        assert_eq!(std::fs::read(&path).unwrap(), b"local");
+        let head = requests.lock()[0].clone();
+        assert!(head.contains("if-none-match: \"v1\""), "{}", head);
+        assert!(head.contains(&format!("if-modified-since: {}", last_modified.to_lowercase())), "{}", head);
+        assert_eq!(requests.lock().len(), 1);
+
+        handle.abort();
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    pub async fn test_bandwidth_schedule() {
+        use std::sync::Arc;
+        use chrono::Weekday;
+        use parking_lot::Mutex;
+        use crate::bandwidth_schedule::{Bandwidth, BandwidthSchedule};
+        use crate::bandwidth_schedule::tests::{at, hm, ManualClock};
+        use crate::sink::tests::serve;
+
+        let url = serve(Arc::new(vec![7u8; 64 * 1024])).await;
+        let clock = Arc::new(ManualClock(Mutex::new(at(1, 10, 0))));
+        let schedule = BandwidthSchedule::new(Bandwidth::Limited(1024 * 1024))
+            .add_window(&[Weekday::Mon], hm(9, 0), hm(17, 0), Bandwidth::Paused)
+            .set_clock(clock.clone());
+        let service = Arc::new(DownloadService::new());
+        service.set_bandwidth_schedule(schedule);
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let config = DownloadConfiguration::new()
+            .set_url(&url)
+            .set_download_in_memory(true)
+            .build()
+            .unwrap();
+        let operation = service.add_downloader(config);
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(operation.status(), DownloadStatus::Pending);
+
+        // Once the paused window ends the download runs at the scheduled rate.
+        *clock.0.lock() = at(1, 17, 0);
+        while !operation.is_done() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(operation.status(), DownloadStatus::Complete);
+        assert_eq!(service.speed_limit(), 1024 * 1024);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    pub async fn test_bandwidth_schedule_holds_streamed_downloads() {
+        use std::sync::Arc;
+        use chrono::Weekday;
+        use parking_lot::Mutex;
+        use tokio::io::AsyncReadExt;
+        use crate::bandwidth_schedule::{Bandwidth, BandwidthSchedule};
+        use crate::bandwidth_schedule::tests::{at, hm, ManualClock};
+        use crate::sink::tests::serve;
+
+        let body: Arc<Vec<u8>> = Arc::new((0..1024 * 1024u32).map(|i| (i % 251) as u8).collect());
+        let url = serve(body.clone()).await;
+        let clock = Arc::new(ManualClock(Mutex::new(at(1, 8, 0))));
+        let schedule = BandwidthSchedule::new(Bandwidth::Limited(256 * 1024))
+            .add_window(&[Weekday::Mon], hm(9, 0), hm(17, 0), Bandwidth::Paused)
+            .set_clock(clock.clone());
+        let service = Arc::new(DownloadService::new());
+        service.set_bandwidth_schedule(schedule);
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let config = DownloadConfiguration::new()
+            .set_url(&url)
+            .set_stream_body(true)
+            .build()
+            .unwrap();
+        let operation = service.add_downloader(config);
+        let mut reader = operation.body().unwrap();
+        let mut received = vec![0u8; 1024];
+        reader.read_exact(&mut received).await.unwrap();
+
+        // The paused window holds the download instead of restarting its stream.
+        *clock.0.lock() = at(1, 10, 0);
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let held = operation.downloaded_size();
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert_eq!(operation.downloaded_size(), held);
+        assert_eq!(operation.status(), DownloadStatus::Download);
+
+        *clock.0.lock() = at(1, 17, 0);
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, *body);
+        while !operation.is_done() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(operation.status(), DownloadStatus::Complete);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    pub async fn test_stop_streamed_download_while_paused() {
+        use std::sync::Arc;
+        use chrono::Weekday;
+        use parking_lot::Mutex;
+        use tokio::io::AsyncReadExt;
+        use crate::bandwidth_schedule::{Bandwidth, BandwidthSchedule};
+        use crate::bandwidth_schedule::tests::{at, hm, ManualClock};
+        use crate::sink::tests::serve;
+
+        let url = serve(Arc::new(vec![5u8; 1024 * 1024])).await;
+        let clock = Arc::new(ManualClock(Mutex::new(at(1, 8, 0))));
+        let schedule = BandwidthSchedule::new(Bandwidth::Limited(256 * 1024))
+            .add_window(&[Weekday::Mon], hm(9, 0), hm(17, 0), Bandwidth::Paused)
+            .set_clock(clock.clone());
+        let service = Arc::new(DownloadService::new());
+        service.set_bandwidth_schedule(schedule);
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let streamed = |service: &DownloadService| {
+            let config = DownloadConfiguration::new()
+                .set_url(&url)
+                .set_stream_body(true)
+                .build()
+                .unwrap();
+            service.add_downloader(config)
+        };
+        let stopped = streamed(&service);
+        let shut_down = streamed(&service);
+        let mut received = vec![0u8; 1024];
+        stopped.body().unwrap().read_exact(&mut received).await.unwrap();
+        shut_down.body().unwrap().read_exact(&mut received).await.unwrap();
+
+        *clock.0.lock() = at(1, 10, 0);
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        stopped.stop();
+        let wait = async {
+            while !stopped.is_done() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        assert!(tokio::time::timeout(std::time::Duration::from_secs(1), wait).await.is_ok());
+        // Let the run loop retire the stopped download.
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+        let report = service.shutdown(std::time::Duration::from_secs(1)).await;
+        assert!(!report.timed_out);
+        assert_eq!(report.interrupted.len(), 1);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    pub async fn test_host_speed_limit_reaches_running_downloads() {
+        use std::sync::Arc;
+        use crate::sink::tests::serve;
+
+        let url = serve(Arc::new(vec![3u8; 4 * 1024 * 1024])).await;
+        let service = Arc::new(DownloadService::new());
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let config = DownloadConfiguration::new()
+            .set_url(&url)
+            .set_download_in_memory(true)
+            .set_download_speed_limit(1024 * 1024)
+            .build()
+            .unwrap();
+        let operation = service.add_downloader(config);
+        while operation.downloaded_size() == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // The host had no limit when the download was added.
+        service.set_host_speed_limit("127.0.0.1", 64 * 1024);
+        let (before, start) = (operation.downloaded_size(), std::time::Instant::now());
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let received = operation.downloaded_size() - before;
+        // A full bucket and one more second's worth, at the host's rate, however long the
+        // sleep actually took.
+        let allowed = (2.0 + start.elapsed().as_secs_f64()) * 64.0 * 1024.0;
+        assert!((received as f64) < allowed, "{} bytes received", received);
+        assert!(!operation.is_done());
+
+        operation.stop();
+        handle.abort();
+    }
+}
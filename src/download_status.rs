@@ -1,6 +1,6 @@
 use std::fmt::{Display, Formatter};
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum DownloadStatus {
     None,
     Pending,
@@ -11,6 +11,9 @@ pub enum DownloadStatus {
     Complete,
     Failed,
     Stop,
+    Decompress,
+    Extract,
+    Patch,
 }
 
 impl Display for DownloadStatus {
@@ -25,6 +28,9 @@ impl Display for DownloadStatus {
             DownloadStatus::Complete => write!(f, "Complete"),
             DownloadStatus::Failed => write!(f, "Failed"),
             DownloadStatus::Stop => write!(f, "Stop"),
+            DownloadStatus::Decompress => write!(f, "Decompress"),
+            DownloadStatus::Extract => write!(f, "Extract"),
+            DownloadStatus::Patch => write!(f, "Patch"),
         }
     }
 }
@@ -40,7 +46,10 @@ impl From<DownloadStatus> for u8 {
             DownloadStatus::FileVerify => 5,
             DownloadStatus::Complete => 6,
             DownloadStatus::Failed => 7,
-            DownloadStatus::Stop => 8
+            DownloadStatus::Stop => 8,
+            DownloadStatus::Decompress => 9,
+            DownloadStatus::Extract => 10,
+            DownloadStatus::Patch => 11,
         }
     }
 }
@@ -57,6 +66,9 @@ impl From<u8> for DownloadStatus {
             6 => DownloadStatus::Complete,
             7 => DownloadStatus::Failed,
             8 => DownloadStatus::Stop,
+            9 => DownloadStatus::Decompress,
+            10 => DownloadStatus::Extract,
+            11 => DownloadStatus::Patch,
             _ => DownloadStatus::None,
         }
     }
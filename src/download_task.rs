@@ -1,12 +1,14 @@
 use std::sync::Arc;
 use futures::StreamExt;
-use reqwest::Client;
-use reqwest::header::RANGE;
+use reqwest::{Client, Method};
+use reqwest::header::{HeaderMap, HeaderValue, RANGE};
 use tokio_util::sync::CancellationToken;
 use crate::chunk::{Chunk};
 use crate::download_configuration::DownloadConfiguration;
+use crate::download_url::DownloadUrl;
 use crate::error::DownloadError;
-use crate::rate_limiter::RateLimiter;
+use crate::rate_limiter::RateLimiterChain;
+use crate::request;
 
 pub struct DownloadTask {}
 
@@ -21,21 +23,29 @@ impl DownloadTask {
         client: Arc<Client>,
         cancel_token: CancellationToken,
         download_chunk: &mut Chunk,
-        rate_limiter: Arc<RateLimiter>,
+        rate_limiter: RateLimiterChain,
+        download_url: Arc<DownloadUrl>,
     ) -> crate::error::Result<()> {
         let retry_count_limit = config.retry_times_on_failure;
         let mut retry_count = 0;
+        let mut refresh_credentials = false;
 
         download_chunk.setup().await?;
 
         'r: loop {
-            let mut request = client.get(config.url());
+            // Without a range request every attempt receives the whole body again.
+            if !download_chunk.range_download && download_chunk.chunk_range.position > download_chunk.chunk_range.start {
+                download_chunk.reset_async().await?;
+            }
+
+            let url = download_url.current().await?;
+            let mut headers = HeaderMap::new();
             if download_chunk.range_download {
                 let range_str = format!("bytes={}-{}", download_chunk.chunk_range.position, download_chunk.chunk_range.end);
-                request = request.header(RANGE, range_str);
+                headers.insert(RANGE, HeaderValue::from_str(&range_str).unwrap());
             }
 
-            let send_future = request.send();
+            let send_future = request::send(&client, &config, Method::GET, &url, headers, refresh_credentials);
             let result = if config.timeout > 0 {
                 tokio::time::timeout(
                     std::time::Duration::from_secs(config.timeout),
@@ -46,23 +56,30 @@ impl DownloadTask {
             };
 
             if cancel_token.is_cancelled() {
+                download_chunk.flush_async().await?;
                 return Ok(());
             }
 
             // Timeout or request error → retry
             let response = match result {
-                Ok(Ok(resp)) => resp,
-                _ => {
+                Ok(Ok(followed)) => followed.response,
+                Ok(Err(DownloadError::Request)) | Err(_) => {
+                    download_url.on_failure(&url);
                     if retry_count >= retry_count_limit {
                         return Err(DownloadError::Request);
                     }
                     retry_count += 1;
                     continue 'r;
                 }
+                Ok(Err(e)) => return Err(e),
             };
 
-
             if let Err(e) = response.error_for_status_ref() {
+                refresh_credentials = request::is_auth_rejected(response.status());
+                download_url.on_failure(&url);
+                if refresh_credentials {
+                    download_url.refresh(&url).await?;
+                }
                 if retry_count >= retry_count_limit {
                     if let Some(status_code) = e.status() {
                         return Err(DownloadError::Response(e.url().as_ref().unwrap().to_string(), status_code.into()));
@@ -80,13 +97,21 @@ impl DownloadTask {
                 let chunk_result = tokio::time::timeout(chunk_timeout, body.next()).await;
 
                 if cancel_token.is_cancelled() {
+                    download_chunk.flush_async().await?;
                     return Ok(());
                 }
 
                 match chunk_result {
                     Ok(Some(Ok(bytes))) => {
-                        // Apply global rate limiting
-                        rate_limiter.acquire(bytes.len() as u64).await;
+                        // Apply the download's, its host's and the service's rate limits. A
+                        // paused limiter may hold this for long, so a stop must not wait on it.
+                        tokio::select! {
+                            _ = rate_limiter.acquire(bytes.len() as u64) => {}
+                            _ = cancel_token.cancelled() => {
+                                download_chunk.flush_async().await?;
+                                return Ok(());
+                            }
+                        }
                         download_chunk.received_bytes_async(&bytes).await?;
                     }
                     Ok(Some(Err(_))) | Err(_) => {
@@ -101,6 +126,7 @@ impl DownloadTask {
                     Ok(None) => break, // Stream finished
                 }
             }
+            download_chunk.finish_async().await?;
             return Ok(());
         }
     }
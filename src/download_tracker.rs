@@ -1,16 +1,24 @@
 use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
+use parking_lot::Mutex;
 use tokio::sync::watch::channel;
+use crate::body_stream;
 use crate::download_receiver::DownloadReceiver;
 use crate::download_sender::DownloadSender;
 use crate::error::DownloadError;
+use crate::memory_budget::Payload;
 
-pub fn new(download_in_memory: bool) -> (DownloadSender, DownloadReceiver) {
+#[allow(clippy::needless_return)]
+pub fn new(download_in_memory: bool, stream_body: bool) -> (DownloadSender, DownloadReceiver) {
     let (download_total_size_sender, download_total_size_receiver) = channel(0u64);
     let (error_sender, error_receiver) = channel(DownloadError::None);
+    let (outcome_sender, outcome_receiver) = channel(None);
+    let (resolved_url_sender, resolved_url_receiver) = channel(None);
+    let (redirect_chain_sender, redirect_chain_receiver) = channel(Vec::new());
+    let (file_path_sender, file_path_receiver) = channel(None);
     let (memory_sender, memory_receiver) = match download_in_memory {
         true => {
-            let (memory_sender, memory_receiver) = channel(vec![]);
+            let (memory_sender, memory_receiver) = channel(Payload::default());
             (Some(memory_sender), Some(memory_receiver))
         }
         false => {
@@ -18,19 +26,40 @@ pub fn new(download_in_memory: bool) -> (DownloadSender, DownloadReceiver) {
         }
     };
 
+    let (body_sender, body) = match stream_body {
+        true => {
+            let (body_sender, body) = body_stream::channel();
+            (Some(body_sender), Some(Arc::new(Mutex::new(Some(body)))))
+        }
+        false => (None, None),
+    };
+
     let downloaded_size = Arc::new(AtomicU64::new(0));
+    let extracted_size = Arc::new(AtomicU64::new(0));
 
     let sender = DownloadSender {
         download_total_size_sender,
         error_sender,
         memory_sender,
+        body_sender: Mutex::new(body_sender),
+        outcome_sender,
+        resolved_url_sender,
+        redirect_chain_sender,
+        file_path_sender,
         downloaded_size: downloaded_size.clone(),
+        extracted_size: extracted_size.clone(),
     };
     let receiver = DownloadReceiver {
         download_total_size_receiver,
         error_receiver,
         memory_receiver,
+        body,
+        outcome_receiver,
+        resolved_url_receiver,
+        redirect_chain_receiver,
+        file_path_receiver,
         downloaded_size,
+        extracted_size,
     };
     return (sender, receiver);
 }
\ No newline at end of file
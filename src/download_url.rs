@@ -0,0 +1,158 @@
+use std::sync::Arc;
+use std::time::Duration;
+use parking_lot::RwLock;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use crate::download_configuration::DownloadConfiguration;
+
+struct UrlState {
+    url: String,
+    obtained_at: Instant,
+}
+
+/// The URL a download currently requests, shared by its HEAD request and all chunks.
+///
+/// Once the probe has resolved redirects, chunks request the final URL directly. If a
+/// request to it fails, or the URL is refreshed, the pin is dropped and chunks go back to
+/// the original URL.
+pub struct DownloadUrl {
+    config: Arc<DownloadConfiguration>,
+    state: Mutex<UrlState>,
+    pinned: RwLock<Option<String>>,
+}
+
+impl DownloadUrl {
+    pub fn new(config: Arc<DownloadConfiguration>) -> Arc<Self> {
+        let url = config.url().to_string();
+        Arc::new(Self {
+            config,
+            state: Mutex::new(UrlState {
+                url,
+                obtained_at: Instant::now(),
+            }),
+            pinned: RwLock::new(None),
+        })
+    }
+
+    /// Get the URL to request, refreshing it first if it has expired.
+    pub async fn current(&self) -> crate::error::Result<String> {
+        let mut state = self.state.lock().await;
+        if self.config.url_provider.is_some() && self.config.url_expiry > 0
+            && state.obtained_at.elapsed() >= Duration::from_secs(self.config.url_expiry) {
+            tracing::info!("download URL expired, requesting a new one");
+            let expired = state.url.clone();
+            self.replace(&mut state, &expired).await?;
+        }
+        if let Some(pinned) = self.pinned.read().clone() {
+            return Ok(pinned);
+        }
+        Ok(state.url.clone())
+    }
+
+    /// Send later requests straight to `final_url`, the target of the redirects from the
+    /// current URL.
+    pub fn pin(&self, final_url: &str) {
+        *self.pinned.write() = Some(final_url.to_string());
+    }
+
+    /// Report that a request to `url` failed. A failing pinned URL is dropped.
+    pub fn on_failure(&self, url: &str) {
+        let mut pinned = self.pinned.write();
+        if pinned.as_deref() == Some(url) {
+            tracing::info!("resolved URL failed, falling back to the original URL");
+            *pinned = None;
+        }
+    }
+
+    /// Replace `rejected` with a fresh URL from the provider. Returns `false` if there
+    /// is no provider. When another chunk has already replaced `rejected` the provider
+    /// is not called again.
+    pub async fn refresh(&self, rejected: &str) -> crate::error::Result<bool> {
+        if self.config.url_provider.is_none() {
+            return Ok(false);
+        }
+        let mut state = self.state.lock().await;
+        self.on_failure(rejected);
+        if state.url == rejected {
+            tracing::info!("download URL rejected, requesting a new one");
+            self.replace(&mut state, rejected).await?;
+        }
+        Ok(true)
+    }
+
+    async fn replace(&self, state: &mut UrlState, expired: &str) -> crate::error::Result<()> {
+        let provider = self.config.url_provider.as_ref().unwrap();
+        state.url = provider.refresh_url(expired).await?;
+        state.obtained_at = Instant::now();
+        // The redirects of the old URL say nothing about where the new one leads.
+        *self.pinned.write() = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use futures::future::BoxFuture;
+    use crate::url_provider::UrlProvider;
+    use super::*;
+
+    struct CountingProvider(AtomicU32);
+
+    impl UrlProvider for CountingProvider {
+        fn refresh_url(&self, _expired: &str) -> BoxFuture<'_, crate::error::Result<String>> {
+            let n = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(async move { Ok(format!("https://example.com/file?sig={}", n)) })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresh_and_expiry() {
+        let provider = Arc::new(CountingProvider(AtomicU32::new(0)));
+        let config = DownloadConfiguration::new()
+            .set_url("https://example.com/file?sig=0")
+            .set_download_in_memory(true)
+            .set_url_provider(provider.clone())
+            .set_url_expiry(60)
+            .build()
+            .unwrap();
+        let url = DownloadUrl::new(Arc::new(config));
+
+        assert_eq!(url.current().await.unwrap(), "https://example.com/file?sig=0");
+
+        // Two chunks rejected with the same URL only trigger one refresh.
+        assert!(url.refresh("https://example.com/file?sig=0").await.unwrap());
+        assert!(url.refresh("https://example.com/file?sig=0").await.unwrap());
+        assert_eq!(provider.0.load(Ordering::SeqCst), 1);
+        assert_eq!(url.current().await.unwrap(), "https://example.com/file?sig=1");
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(url.current().await.unwrap(), "https://example.com/file?sig=2");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expiry_drops_pinned_redirect() {
+        let provider = Arc::new(CountingProvider(AtomicU32::new(0)));
+        let config = DownloadConfiguration::new()
+            .set_url("https://example.com/file?sig=0")
+            .set_download_in_memory(true)
+            .set_url_provider(provider.clone())
+            .set_url_expiry(60)
+            .build()
+            .unwrap();
+        let url = DownloadUrl::new(Arc::new(config));
+
+        url.pin("https://cdn.example.com/file?sig=0");
+        assert_eq!(url.current().await.unwrap(), "https://cdn.example.com/file?sig=0");
+
+        // An expired URL is refreshed even though its redirect target is pinned.
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(url.current().await.unwrap(), "https://example.com/file?sig=1");
+        assert_eq!(provider.0.load(Ordering::SeqCst), 1);
+
+        // A rejected pinned URL is dropped and the original one refreshed.
+        url.pin("https://cdn.example.com/file?sig=1");
+        assert!(url.refresh("https://cdn.example.com/file?sig=1").await.unwrap());
+        assert_eq!(url.current().await.unwrap(), "https://example.com/file?sig=1");
+    }
+}
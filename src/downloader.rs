@@ -1,17 +1,31 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use reqwest::Client;
 use parking_lot::RwLock;
 use tokio::{fs, spawn};
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use crate::download_status::DownloadStatus;
-use crate::download_configuration::DownloadConfiguration;
+use crate::download_configuration::{DownloadConfiguration, ExistingFilePolicy};
 use crate::download_sender::DownloadSender;
-use crate::{chunk, chunk_hub, remote_file};
+use crate::{chunk, chunk_hub, chunk_metadata, compression, delta, existing_file, extract, file_name, patch, remote_file};
+use crate::chunk::Chunk;
+use crate::delta::BlockIndex;
+use crate::extract::ArchiveFormat;
+use crate::patch::PatchFormat;
+use crate::chunk_metadata::Validators;
+use crate::download_outcome::DownloadOutcome;
+use crate::download_cache::DownloadCache;
+use crate::memory_budget::{MemoryBudget, Reservation};
+use crate::download_url::DownloadUrl;
+use crate::existing_file::Destination;
+use crate::remote_file::RemoteFile;
+use crate::file_lock::FileLock;
 use crate::error::DownloadError;
-use crate::verify::file_verify::FileVerify;
+use crate::verify::file_verify::{FileVerify, VerifyTarget};
 use crate::verify::file_verify;
-use crate::rate_limiter::RateLimiter;
+use crate::rate_limiter::{RateLimiter, RateLimiterChain};
 use tracing;
 
 pub struct Downloader {
@@ -21,67 +35,113 @@ pub struct Downloader {
     cancel_token: RwLock<CancellationToken>,
     sender: Arc<DownloadSender>,
     thread_handle: RwLock<Option<JoinHandle<()>>>,
+    /// Set once the remote file is known to support resuming from the data on disk.
+    resumable: Arc<AtomicBool>,
+    journal_id: Option<u64>,
+    cache: Option<Arc<DownloadCache>>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    /// Limits this download to its configured receive speed.
+    rate_limiter: Arc<RateLimiter>,
+    /// Limiters shared with other downloads, such as its host's and the service's.
+    shared_rate_limiters: Vec<Arc<RateLimiter>>,
+}
+
+/// State shared with the task spawned by [`Downloader::start_download`].
+struct DownloadContext {
+    config: Arc<DownloadConfiguration>,
+    client: Arc<Client>,
+    cancel_token: CancellationToken,
+    sender: Arc<DownloadSender>,
+    status: Arc<RwLock<DownloadStatus>>,
+    resumable: Arc<AtomicBool>,
+    cache: Option<Arc<DownloadCache>>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    rate_limiter: RateLimiterChain,
+    /// Held until the download ends so no other downloader, in this or another
+    /// process, writes the same temp, chunk or metadata files.
+    file_lock: Option<FileLock>,
+}
+
+/// How [`start_download_file`] obtained the file.
+enum Fetched {
+    /// Downloaded to the temp path; `cache_key` names the cache entry to store it under.
+    Downloaded { cache_key: Option<String>, validators: Validators },
+    /// Placed at the destination from the cache.
+    Cached,
+    /// The server reported that the local file is current.
+    NotModified,
+    /// The local file matches the size the server reported.
+    Skipped,
+}
+
+impl Fetched {
+    fn interrupted() -> Self {
+        Fetched::Downloaded { cache_key: None, validators: Validators::default() }
+    }
 }
 
 impl Downloader {
     pub fn new(config: DownloadConfiguration, client: Arc<Client>, sender: Arc<DownloadSender>) -> Downloader {
+        if config.writes_file() {
+            let _ = sender.file_path_sender.send(config.path.clone());
+        }
         let config = Arc::new(config);
-        let downloader = Downloader {
+        Downloader {
             config: config.clone(),
             client,
             download_status: Arc::new(RwLock::new(DownloadStatus::None)),
             cancel_token: RwLock::new(CancellationToken::new()),
             sender,
             thread_handle: RwLock::new(None),
-        };
-        downloader
+            resumable: Arc::new(AtomicBool::new(false)),
+            journal_id: None,
+            cache: None,
+            memory_budget: None,
+            rate_limiter: RateLimiter::with_burst(config.receive_bytes_per_second, config.receive_burst_bytes),
+            shared_rate_limiters: Vec::new(),
+        }
     }
 
     pub fn start_download(&self) {
-        let config = self.config.clone();
-        let client = self.client.clone();
         let new_token = CancellationToken::new();
         *self.cancel_token.write() = new_token.clone();
-        let cancel_token = new_token;
-        let sender = self.sender.clone();
-        let download_status = self.download_status.clone();
+        // A file name inferred by an earlier run is kept so the download resumes into it.
+        let config = match (&self.config.path, self.file_path()) {
+            (None, Some(path)) => Arc::new(self.config.with_file_path(path)),
+            _ => self.config.clone(),
+        };
+        let mut context = DownloadContext {
+            config,
+            client: self.client.clone(),
+            cancel_token: new_token,
+            sender: self.sender.clone(),
+            status: self.download_status.clone(),
+            resumable: self.resumable.clone(),
+            cache: self.cache.clone(),
+            memory_budget: self.memory_budget.clone(),
+            rate_limiter: self.rate_limiter_chain(),
+            file_lock: None,
+        };
         let handle = spawn(async move {
-            if let Err(e) = start_download_file(config.clone(),
-                                                client.clone(),
-                                                cancel_token.clone(),
-                                                sender.clone(),
-                                                download_status.clone()).await {
-                tracing::error!(error = %e, "download failed");
-                let _ = sender.error_sender.send(e);
-                *download_status.write() = DownloadStatus::Failed;
-                return;
-            }
-
-            if cancel_token.is_cancelled() {
-                return;
-            }
-
-            if !config.download_in_memory {
-                if config.file_verify != FileVerify::None {
-                    *download_status.write() = DownloadStatus::FileVerify;
-                    tracing::info!("verifying downloaded file");
-                    if let Err(e) = file_verify::file_validate(&config.file_verify, config.get_file_temp_path()).await {
-                        tracing::error!(error = %e, "file verification failed");
-                        let _ = sender.error_sender.send(e);
-                        *download_status.write() = DownloadStatus::Failed;
-                        return;
-                    }
-                }
-
-                if let Err(e) = fs::rename(config.get_file_temp_path(), config.get_file_path()).await {
-                    let _ = sender.error_sender.send(DownloadError::FileRename(format!("file rename failed {}", e)));
-                    *download_status.write() = DownloadStatus::Failed;
+            let outcome = match download(&mut context).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    context.sender.finish_body(false);
+                    tracing::error!(error = %e, "download failed");
+                    let _ = context.sender.error_sender.send(e);
+                    *context.status.write() = DownloadStatus::Failed;
                     return;
                 }
+            };
+
+            context.sender.finish_body(!context.cancel_token.is_cancelled());
+            if context.cancel_token.is_cancelled() {
+                return;
             }
 
-            *download_status.write() = DownloadStatus::Complete;
-            tracing::info!("download complete");
+            let _ = context.sender.outcome_sender.send(Some(outcome));
+            *context.status.write() = DownloadStatus::Complete;
+            tracing::info!(%outcome, "download complete");
         });
         *self.thread_handle.write() = Some(handle);
     }
@@ -90,13 +150,77 @@ impl Downloader {
         if let Some(handle) = self.thread_handle.read().as_ref() {
             return handle.is_finished();
         }
-        return false;
+        *self.download_status.read() == DownloadStatus::Failed
     }
 
     pub fn status(&self) -> DownloadStatus {
         *self.download_status.read()
     }
 
+    pub fn config(&self) -> &DownloadConfiguration {
+        &self.config
+    }
+
+    /// The destination file path, once known.
+    pub fn file_path(&self) -> Option<PathBuf> {
+        self.sender.file_path_sender.borrow().clone()
+    }
+
+    pub fn downloaded_size(&self) -> u64 {
+        self.sender.downloaded_size.load(Ordering::Relaxed)
+    }
+
+    pub fn total_size(&self) -> u64 {
+        *self.sender.download_total_size_sender.borrow()
+    }
+
+    /// Returns `true` if the data written so far can be resumed by a later download.
+    pub fn is_resumable(&self) -> bool {
+        self.resumable.load(Ordering::Relaxed)
+    }
+
+    /// The id of this download in the service's [`QueueStore`](crate::queue_store::QueueStore), if journaled.
+    pub fn journal_id(&self) -> Option<u64> {
+        self.journal_id
+    }
+
+    pub fn set_journal_id(&mut self, journal_id: u64) {
+        self.journal_id = Some(journal_id);
+    }
+
+    /// Share a [`DownloadCache`] with this download. Ignored for in-memory downloads.
+    pub fn set_cache(&mut self, cache: Arc<DownloadCache>) {
+        self.cache = Some(cache);
+    }
+
+    /// Count this download against a shared [`MemoryBudget`]. Only applies to in-memory downloads.
+    pub fn set_memory_budget(&mut self, memory_budget: Arc<MemoryBudget>) {
+        self.memory_budget = Some(memory_budget);
+    }
+
+    /// Change this download's receive speed limit, 0 for unlimited. Takes effect on the
+    /// running transfer without restarting it.
+    pub fn set_speed_limit(&self, bytes_per_second: u64) {
+        self.rate_limiter.set_bytes_per_second(bytes_per_second);
+    }
+
+    /// Also enforce `rate_limiters`, which are shared with other downloads.
+    pub fn set_shared_rate_limiters(&mut self, rate_limiters: Vec<Arc<RateLimiter>>) {
+        self.shared_rate_limiters = rate_limiters;
+    }
+
+    fn rate_limiter_chain(&self) -> RateLimiterChain {
+        let mut limiters = vec![self.rate_limiter.clone()];
+        limiters.extend(self.shared_rate_limiters.iter().cloned());
+        RateLimiterChain::new(limiters)
+    }
+
+    /// Mark the download as failed without starting it.
+    pub fn fail(&mut self, error: DownloadError) {
+        let _ = self.sender.error_sender.send(error);
+        *self.download_status.write() = DownloadStatus::Failed;
+    }
+
     pub async fn is_pending_async(&self) -> bool {
         return *self.download_status.read() == DownloadStatus::Pending;
     }
@@ -114,83 +238,489 @@ impl Downloader {
         *self.download_status.write() = DownloadStatus::Stop;
     }
 
+    /// Stop the running transfer and mark the download pending so it can be started again.
+    /// The stopped run's task is let go, so the download does not look done while queued.
+    pub fn requeue(&self) {
+        self.cancel_token.read().cancel();
+        *self.download_status.write() = DownloadStatus::Pending;
+        *self.thread_handle.write() = None;
+    }
+
     pub async fn stop_async(&self) {
         self.cancel_token.read().cancel();
         *self.download_status.write() = DownloadStatus::Stop;
     }
 }
 
+async fn download(context: &mut DownloadContext) -> crate::error::Result<DownloadOutcome> {
+    let outcome = obtain(context).await?;
+    if context.cancel_token.is_cancelled() || context.config.extract_to.is_none() {
+        return Ok(outcome);
+    }
+    // Skipped and up-to-date archives were extracted when they were downloaded.
+    if let DownloadOutcome::Downloaded | DownloadOutcome::Cached = outcome {
+        extract_archive(context).await?;
+    }
+    Ok(outcome)
+}
+
+/// Place the file at its destination, from the cache or by downloading it.
+async fn obtain(context: &mut DownloadContext) -> crate::error::Result<DownloadOutcome> {
+    let mut probed = None;
+    if context.config.writes_file() {
+        // Downloads into a directory are probed first to learn the file name.
+        let (path, remote_length) = match &context.config.path {
+            Some(path) => (path.clone(), None),
+            None => {
+                let (download_url, remote_file) = probe(context, None).await?;
+                if context.cancel_token.is_cancelled() {
+                    return Ok(DownloadOutcome::Downloaded);
+                }
+                let path = infer_path(&context.config, &remote_file);
+                let remote_length = remote_file.total_length;
+                probed = Some((download_url, remote_file));
+                (path, Some(remote_length))
+            }
+        };
+
+        match existing_file::check(&context.config, &path, remote_length).await? {
+            Destination::Skip(path) => return Ok(skip(context, path).await),
+            Destination::Path(resolved) => {
+                if context.config.path.as_ref() != Some(&resolved) {
+                    tracing::info!(path = %resolved.display(), "resolved destination file");
+                    context.config = Arc::new(context.config.with_file_path(&resolved));
+                }
+                let _ = context.sender.file_path_sender.send(Some(resolved));
+            }
+        }
+    }
+
+    if context.config.writes_file() {
+        context.file_lock = Some(FileLock::acquire(context.config.get_file_path())?);
+    }
+    let config = &context.config;
+
+    let cache = match config.writes_file() {
+        true => context.cache.as_ref(),
+        false => None,
+    };
+    let hash_key = DownloadCache::hash_key(&config.file_verify).map(|key| cache_variant(config, key));
+    if let (Some(cache), Some(key)) = (cache, &hash_key) {
+        if let Some(size) = cache.fetch(key, config.get_file_temp_path()).await {
+            on_local_file(context, size);
+            return place_cached(context).await;
+        }
+    }
+
+    let fetched = start_download_file(context, hash_key, probed).await?;
+
+    if context.cancel_token.is_cancelled() || !config.writes_file() {
+        return Ok(DownloadOutcome::Downloaded);
+    }
+
+    let (cache_key, validators) = match fetched {
+        Fetched::Cached => return place_cached(context).await,
+        Fetched::NotModified => return Ok(DownloadOutcome::UpToDate),
+        Fetched::Skipped => return Ok(skip(context, config.get_file_path().to_path_buf()).await),
+        Fetched::Downloaded { cache_key, validators } => (cache_key, validators),
+    };
+
+    // A patch download is verified as the patched file, not as the patch.
+    let verify_processed = config.patch_format.is_some()
+        || (config.decompression.is_some() && config.verify_target == VerifyTarget::Decompressed);
+    if !verify_processed {
+        verify(context).await?;
+    }
+
+    if let (Some(compression), false) = (config.decompression, config.streaming_decompression) {
+        *context.status.write() = DownloadStatus::Decompress;
+        tracing::info!(%compression, "decompressing downloaded file");
+        let decoded_path = PathBuf::from(format!("{}.decoded", config.get_file_path().display()));
+        let result = compression::decompress_file(compression, config.get_file_temp_path(), &decoded_path).await;
+        if let Err(e) = result {
+            tracing::error!(error = %e, "decompression failed");
+            let _ = fs::remove_file(&decoded_path).await;
+            return Err(e);
+        }
+        if let Err(e) = fs::rename(&decoded_path, config.get_file_temp_path()).await {
+            return Err(DownloadError::FileRename(format!("file rename failed {}", e)));
+        }
+    }
+
+    if let (Some(format), Some(base)) = (config.patch_format, &config.patch_base) {
+        apply_patch(context, format, base).await?;
+    }
+
+    if verify_processed {
+        verify(context).await?;
+    }
+
+    if let (Some(cache), Some(key)) = (cache, cache_key) {
+        if let Err(e) = cache.insert(&key, config.get_file_temp_path()).await {
+            tracing::warn!(error = %e, "failed to insert download into cache");
+        }
+    }
+
+    let path = match place_file(context).await? {
+        Destination::Skip(path) => return Ok(skip(context, path).await),
+        Destination::Path(path) => path,
+    };
+
+    if config.conditional_download && !validators.is_empty() {
+        if let Err(e) = chunk_metadata::save_validators(&path, &validators).await {
+            tracing::warn!(error = %e, "failed to save cache validators");
+        }
+    }
+
+    Ok(DownloadOutcome::Downloaded)
+}
+
+/// Move the file at the temp path to its destination, applying the existing file policy
+/// again in case a file appeared there in the meantime.
+async fn place_file(context: &DownloadContext) -> crate::error::Result<Destination> {
+    let config = &context.config;
+    let destination = existing_file::place(config, config.get_file_temp_path(), config.get_file_path()).await?;
+    if let Destination::Path(path) = &destination {
+        if path != config.get_file_path() {
+            tracing::info!(path = %path.display(), "destination appeared during download, kept both");
+            let _ = context.sender.file_path_sender.send(Some(path.clone()));
+        }
+    }
+    Ok(destination)
+}
+
+/// Place a file fetched from the cache into the temp path at its destination.
+async fn place_cached(context: &DownloadContext) -> crate::error::Result<DownloadOutcome> {
+    match place_file(context).await? {
+        Destination::Skip(path) => Ok(skip(context, path).await),
+        Destination::Path(_) => Ok(DownloadOutcome::Cached),
+    }
+}
+
+/// Replace the downloaded patch at the temp path with the patched `base` file.
+async fn apply_patch(context: &DownloadContext, format: PatchFormat, base: &Path) -> crate::error::Result<()> {
+    let config = &context.config;
+    *context.status.write() = DownloadStatus::Patch;
+    tracing::info!(%format, base = %base.display(), "applying patch");
+
+    let patched_path = PathBuf::from(format!("{}.patched", config.get_file_path().display()));
+    let base = base.to_path_buf();
+    let patch = config.get_file_temp_path().to_path_buf();
+    let target = patched_path.clone();
+    let result = tokio::task::spawn_blocking(move || patch::apply(format, &base, &patch, &target)).await;
+    match result {
+        Ok(Ok(size)) => tracing::info!(size, "patch applied"),
+        Ok(Err(e)) => {
+            let _ = fs::remove_file(&patched_path).await;
+            return Err(e);
+        }
+        Err(_) => {
+            let _ = fs::remove_file(&patched_path).await;
+            return Err(DownloadError::Patch);
+        }
+    }
+
+    if let Err(e) = fs::rename(&patched_path, config.get_file_temp_path()).await {
+        return Err(DownloadError::FileRename(format!("file rename failed {}", e)));
+    }
+    Ok(())
+}
+
+/// Extract the downloaded archive into the configured directory.
+async fn extract_archive(context: &DownloadContext) -> crate::error::Result<()> {
+    let config = &context.config;
+    let archive = context.sender.file_path_sender.borrow().clone()
+        .unwrap_or_else(|| config.get_file_path().to_path_buf());
+    let target = config.extract_to.clone().unwrap();
+    let Some(format) = config.archive_format.or_else(|| ArchiveFormat::from_path(&archive)) else {
+        return Err(DownloadError::Extract(format!("unknown archive format of {}", archive.display())));
+    };
+
+    *context.status.write() = DownloadStatus::Extract;
+    tracing::info!(%format, target = %target.display(), "extracting archive");
+    context.sender.extracted_size.store(0, Ordering::Relaxed);
+    let extracted = context.sender.extracted_size.clone();
+    let cancel_token = context.cancel_token.clone();
+    let source = archive.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        extract::extract(format, &source, &target, &extracted, &cancel_token)
+    }).await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, "archive extraction failed");
+            return Err(e);
+        }
+        Err(e) => return Err(DownloadError::Extract(e.to_string())),
+    }
+
+    if config.remove_archive && !context.cancel_token.is_cancelled() {
+        if let Err(e) = fs::remove_file(&archive).await {
+            tracing::warn!(error = %e, "failed to remove extracted archive");
+        }
+        let _ = chunk_metadata::delete_validators(&archive).await;
+    }
+    Ok(())
+}
+
+async fn verify(context: &DownloadContext) -> crate::error::Result<()> {
+    let config = &context.config;
+    if config.file_verify == FileVerify::None {
+        return Ok(());
+    }
+    *context.status.write() = DownloadStatus::FileVerify;
+    tracing::info!("verifying downloaded file");
+    if let Err(e) = file_verify::file_validate(&config.file_verify, config.get_file_temp_path()).await {
+        tracing::error!(error = %e, "file verification failed");
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Cache entries hold the final file, so decompressed downloads use their own keys.
+fn cache_variant(config: &DownloadConfiguration, key: String) -> String {
+    match config.decompression {
+        Some(compression) => format!("{}-{}", key, compression),
+        None => key,
+    }
+}
+
+/// Report an already complete local file of `size` bytes as the download progress.
+fn on_local_file(context: &DownloadContext, size: u64) {
+    let _ = context.sender.download_total_size_sender.send(size);
+    context.sender.downloaded_size.store(size, Ordering::Relaxed);
+}
+
+/// Choose the path of a directory download from the probed `remote_file`.
+fn infer_path(config: &DownloadConfiguration, remote_file: &RemoteFile) -> PathBuf {
+    let name = remote_file.file_name.as_deref().and_then(file_name::sanitize)
+        .or_else(|| file_name::from_url(&remote_file.final_url).as_deref().and_then(file_name::sanitize))
+        .or_else(|| file_name::sanitize(&config.default_file_name))
+        .unwrap_or_else(|| "download".to_string());
+    config.directory.as_ref().unwrap().join(name)
+}
+
+/// Complete without downloading because the existing file at `path` is kept.
+async fn skip(context: &DownloadContext, path: PathBuf) -> DownloadOutcome {
+    tracing::info!(path = %path.display(), "destination exists, skipping download");
+    if let Ok(metadata) = fs::metadata(&path).await {
+        on_local_file(context, metadata.len());
+    }
+    let _ = context.sender.file_path_sender.send(Some(path));
+    DownloadOutcome::Skipped
+}
+
+/// Send the HEAD request, pin the URL it was redirected to and report the resolved URL.
+async fn probe(context: &DownloadContext, validators: Option<&Validators>) -> crate::error::Result<(Arc<DownloadUrl>, RemoteFile)> {
+    let config = &context.config;
+    let sender = &context.sender;
+
+    *context.status.write() = DownloadStatus::Head;
+    tracing::info!(url = config.url(), conditional = validators.is_some(), "sending HEAD request");
+
+    // Shared by the HEAD request and every chunk so a refreshed URL is used by all.
+    let download_url = DownloadUrl::new(config.clone());
+    let remote_file = remote_file::head(&context.client, config, &download_url, validators).await?;
+
+    if !remote_file.redirects.is_empty() {
+        tracing::info!(redirects = remote_file.redirects.len(), "resolved download URL");
+        download_url.pin(&remote_file.final_url);
+    }
+    let _ = sender.resolved_url_sender.send(Some(remote_file.final_url.clone()));
+    let _ = sender.redirect_chain_sender.send(remote_file.redirects.clone());
+    Ok((download_url, remote_file))
+}
+
 async fn start_download_file(
-    config: Arc<DownloadConfiguration>,
-    client: Arc<Client>,
-    cancel_token: CancellationToken,
-    sender: Arc<DownloadSender>,
-    status: Arc<RwLock<DownloadStatus>>) -> crate::error::Result<()> {
+    context: &DownloadContext,
+    hash_key: Option<String>,
+    probed: Option<(Arc<DownloadUrl>, RemoteFile)>) -> crate::error::Result<Fetched> {
+    let config = &context.config;
+    let cancel_token = &context.cancel_token;
+    let sender = &context.sender;
+    let status = &context.status;
+
     if cancel_token.is_cancelled() {
-        return Ok(());
+        return Ok(Fetched::interrupted());
     }
 
-    *status.write() = DownloadStatus::Head;
-    tracing::info!(url = config.url(), "sending HEAD request");
+    // Validators are only sent while the file they describe is still in place.
+    let validators = match config.conditional_download && config.writes_file() {
+        true => match fs::try_exists(config.get_file_path()).await {
+            Ok(true) => chunk_metadata::get_validators(config.get_file_path()).await,
+            _ => None,
+        },
+        false => None,
+    };
 
-    let remote_file = remote_file::head(&client, &config).await?;
+    // An earlier unconditional probe is reused unless there are validators to send.
+    let (download_url, remote_file) = match (probed, &validators) {
+        (Some(probed), None) => probed,
+        _ => probe(context, validators.as_ref()).await?,
+    };
 
     if cancel_token.is_cancelled() {
-        return Ok(());
+        return Ok(Fetched::interrupted());
     }
 
+    if remote_file.not_modified {
+        tracing::info!("local file is up to date");
+        if let Ok(metadata) = fs::metadata(config.get_file_path()).await {
+            on_local_file(context, metadata.len());
+        }
+        return Ok(Fetched::NotModified);
+    }
+
+    // Without a hash, whether the existing file is the same is only known after the probe.
+    if config.existing_file_policy == ExistingFilePolicy::SkipIfSame
+        && config.file_verify == FileVerify::None
+        && config.writes_file() {
+        let check = existing_file::check(config, config.get_file_path(), Some(remote_file.total_length)).await?;
+        if let Destination::Skip(_) = check {
+            return Ok(Fetched::Skipped);
+        }
+    }
+
+    // The result of a patch depends on the local base file, not only on the patch's ETag.
+    let cache_key = match (hash_key, &remote_file.etag) {
+        (Some(key), _) => Some(key),
+        (None, Some(etag)) if context.cache.is_some() && config.writes_file() && config.patch_format.is_none() => {
+            let key = cache_variant(config, DownloadCache::etag_key(config.url(), etag));
+            let cache = context.cache.as_ref().unwrap();
+            if let Some(size) = cache.fetch(&key, config.get_file_temp_path()).await {
+                on_local_file(context, size);
+                return Ok(Fetched::Cached);
+            }
+            Some(key)
+        }
+        (None, _) => None,
+    };
+
     *status.write() = DownloadStatus::Download;
 
     let _ = sender.download_total_size_sender.send(remote_file.total_length);
     tracing::info!(total_size = remote_file.total_length, "starting download");
 
+    let remote_version = match config.remote_version {
+        0 => remote_file.last_modified_time,
+        _ => config.remote_version
+    };
+    context.resumable.store(
+        config.writes_file()
+            && !config.is_streaming_decompression()
+            && config.range_download
+            && remote_file.support_range_download
+            && remote_version != 0,
+        Ordering::Relaxed);
+
+    let validators = remote_file.validators();
+
+    // A delta download needs range requests and the exact file version its index describes.
+    if let (Some(index), Some(base)) = (&config.delta_index, &config.delta_base) {
+        let usable = remote_file.support_range_download
+            && remote_file.total_length == index.file_size
+            && fs::try_exists(base).await.unwrap_or(false);
+        if usable {
+            download_delta(context, &download_url, index.clone(), base.clone(), remote_version).await?;
+            if cancel_token.is_cancelled() {
+                return Ok(Fetched::interrupted());
+            }
+            return Ok(Fetched::Downloaded { cache_key, validators });
+        }
+        tracing::warn!("delta download unavailable, downloading the whole file");
+    }
+
     // Pass the shared AtomicU64 counter to chunk_hub::validate.
     // Each chunk will atomically increment this counter as data arrives.
     // The receiver side reads the same counter for instant progress.
-    let chunks = chunk_hub::validate(&config, remote_file, sender.downloaded_size.clone()).await?;
+    // In-memory downloads wait for room in the memory budget once their length is known.
+    let reservation = match (&context.memory_budget, config.download_in_memory) {
+        (Some(budget), true) => tokio::select! {
+            reservation = budget.reserve(remote_file.total_length) => reservation?,
+            _ = cancel_token.cancelled() => return Ok(Fetched::interrupted()),
+        },
+        _ => Reservation::Memory(None),
+    };
+    let chunks = chunk_hub::validate(config, remote_file, sender, cancel_token, reservation).await?;
+    let chunk_length = chunks.len();
+    download_chunks(context, chunks, &download_url).await?;
+
+    if cancel_token.is_cancelled() {
+        return Ok(Fetched::interrupted());
+    }
+
+    *status.write() = DownloadStatus::DownloadPost;
+    chunk_hub::on_download_post(config, chunk_length).await?;
+
+    Ok(Fetched::Downloaded { cache_key, validators })
+}
 
-    // Create global rate limiter from config
-    let rate_limiter = RateLimiter::new(config.receive_bytes_per_second);
+/// Download the chunks that are not complete yet, concurrently.
+async fn download_chunks(context: &DownloadContext, chunks: Vec<Chunk>, download_url: &Arc<DownloadUrl>) -> crate::error::Result<()> {
+    let config = &context.config;
 
     let mut handles = Vec::with_capacity(chunks.len());
-    let chunk_length = chunks.len();
     for chunk in chunks {
         if chunk.valid {
             continue;
         }
-        let sender = sender.clone();
-        let rl = rate_limiter.clone();
         let handle = spawn(
             chunk::start_download(
                 config.clone(),
-                client.clone(),
+                context.client.clone(),
                 chunk,
-                sender,
-                cancel_token.clone(),
-                rl)
+                context.cancel_token.clone(),
+                context.rate_limiter.clone(),
+                download_url.clone())
         );
         handles.push(handle);
     }
 
     for handle in handles {
         match handle.await {
-            Ok(result) => {
-                if let Err(e) = result {
-                    return Err(e);
-                }
-            }
+            Ok(result) => result?,
             Err(_) => {
                 return Err(DownloadError::ChunkDownloadHandle);
             }
         }
     }
+    Ok(())
+}
 
-    if cancel_token.is_cancelled() {
+/// Build the file at the temp path from the blocks of `base` that match `index`,
+/// downloading only the other byte ranges.
+async fn download_delta(
+    context: &DownloadContext,
+    download_url: &Arc<DownloadUrl>,
+    index: Arc<BlockIndex>,
+    base: PathBuf,
+    remote_version: i64) -> crate::error::Result<()> {
+    let config = &context.config;
+    let sender = &context.sender;
+
+    let (plan_index, plan_base) = (index.clone(), base.clone());
+    let plan = tokio::task::spawn_blocking(move || delta::plan(&plan_index, &plan_base)).await
+        .map_err(|_| DownloadError::DownloadTask)??;
+    let ranges = plan.missing_ranges(&index);
+    let missing_size: u64 = ranges.iter().map(|range| range.end + 1 - range.start).sum();
+    tracing::info!(reused_size = plan.reused_size(&index), missing_size, ranges = ranges.len(), "starting delta download");
+    let _ = sender.download_total_size_sender.send(missing_size);
+
+    let chunks = chunk_hub::validate_ranges(config, remote_version, ranges.clone(), sender.downloaded_size.clone()).await?;
+    let range_files: Vec<PathBuf> = chunks.iter().filter_map(|chunk| chunk.file_path.clone()).collect();
+    download_chunks(context, chunks, download_url).await?;
+
+    if context.cancel_token.is_cancelled() {
         return Ok(());
     }
 
-    *status.write() = DownloadStatus::DownloadPost;
-    chunk_hub::on_download_post(&config, chunk_length).await?;
+    *context.status.write() = DownloadStatus::DownloadPost;
+    let target = config.get_file_temp_path().to_path_buf();
+    let files = range_files.clone();
+    tokio::task::spawn_blocking(move || delta::assemble(&index, &plan, &base, &ranges, &files, &target)).await
+        .map_err(|_| DownloadError::DownloadTask)??;
 
+    chunk_hub::delete_chunk_files(config, range_files.len()).await?;
+    let _ = chunk_metadata::delete_metadata(config.get_file_path()).await;
     Ok(())
-}
\ No newline at end of file
+}
@@ -43,6 +43,30 @@ pub enum DownloadError {
     ChunkDownloadHandle,
     #[error("configuration error: {0}")]
     Config(String),
+    #[error("download service has been shut down")]
+    ServiceShutdown,
+    #[error("duplicate download: {0}")]
+    Duplicate(String),
+    #[error("destination is locked by another downloader: {0}")]
+    FileLocked(String),
+    #[error("failed to obtain credentials: {0}")]
+    Credentials(String),
+    #[error("failed to build HTTP client: {0}")]
+    Client(String),
+    #[error("too many redirects for {0}")]
+    TooManyRedirects(String),
+    #[error("destination file already exists: {0}")]
+    FileExists(String),
+    #[error("decompression failed: {0}")]
+    Decompress(String),
+    #[error("archive extraction failed: {0}")]
+    Extract(String),
+    #[error("body stream was closed by the reader")]
+    BodyStreamClosed,
+    #[error("download ended before the whole body was streamed")]
+    BodyStreamInterrupted,
+    #[error("memory budget: {0}")]
+    MemoryBudget(String),
 }
 
 pub type Result<T> = core::result::Result<T, DownloadError>;
\ No newline at end of file
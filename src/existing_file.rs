@@ -0,0 +1,181 @@
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use crate::download_configuration::{DownloadConfiguration, ExistingFilePolicy};
+use crate::error::DownloadError;
+use crate::verify::file_verify::{self, FileVerify};
+
+/// Where a download should be written according to its [`ExistingFilePolicy`].
+pub enum Destination {
+    /// Download to this path.
+    Path(PathBuf),
+    /// A file already exists at this path and is kept.
+    Skip(PathBuf),
+}
+
+/// Evaluate the policy for `path` before downloading. `remote_length` is the size the
+/// probe reported, if it has been sent.
+pub async fn check(config: &DownloadConfiguration, path: &Path, remote_length: Option<u64>) -> crate::error::Result<Destination> {
+    if !exists(path).await {
+        return Ok(Destination::Path(path.to_path_buf()));
+    }
+    match config.existing_file_policy {
+        ExistingFilePolicy::Overwrite | ExistingFilePolicy::Backup => Ok(Destination::Path(path.to_path_buf())),
+        ExistingFilePolicy::Skip => Ok(Destination::Skip(path.to_path_buf())),
+        ExistingFilePolicy::SkipIfSame => match matches_remote(config, path, remote_length).await {
+            true => Ok(Destination::Skip(path.to_path_buf())),
+            false => Ok(Destination::Path(path.to_path_buf())),
+        },
+        ExistingFilePolicy::Fail => Err(DownloadError::FileExists(path.display().to_string())),
+        ExistingFilePolicy::Rename => Ok(Destination::Path(free_path(path).await)),
+    }
+}
+
+/// Move the downloaded `temp` file to `path`, evaluating the policy again in case a file
+/// appeared there during the download. Returns where the file ended up.
+pub async fn place(config: &DownloadConfiguration, temp: &Path, path: &Path) -> crate::error::Result<Destination> {
+    if !exists(path).await {
+        rename(temp, path).await?;
+        return Ok(Destination::Path(path.to_path_buf()));
+    }
+    match config.existing_file_policy {
+        ExistingFilePolicy::Overwrite => {
+            rename(temp, path).await?;
+            Ok(Destination::Path(path.to_path_buf()))
+        }
+        ExistingFilePolicy::Skip => {
+            let _ = fs::remove_file(temp).await;
+            Ok(Destination::Skip(path.to_path_buf()))
+        }
+        ExistingFilePolicy::SkipIfSame => {
+            if same_content(temp, path).await {
+                let _ = fs::remove_file(temp).await;
+                return Ok(Destination::Skip(path.to_path_buf()));
+            }
+            rename(temp, path).await?;
+            Ok(Destination::Path(path.to_path_buf()))
+        }
+        ExistingFilePolicy::Fail => {
+            let _ = fs::remove_file(temp).await;
+            Err(DownloadError::FileExists(path.display().to_string()))
+        }
+        ExistingFilePolicy::Rename => {
+            let free = free_path(path).await;
+            rename(temp, &free).await?;
+            Ok(Destination::Path(free))
+        }
+        ExistingFilePolicy::Backup => {
+            rename(path, &backup_path(path)).await?;
+            rename(temp, path).await?;
+            Ok(Destination::Path(path.to_path_buf()))
+        }
+    }
+}
+
+/// The path an existing file is moved to by [`ExistingFilePolicy::Backup`].
+pub fn backup_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.bak", path.display()))
+}
+
+/// Find the first of `path`, `name (1).ext`, `name (2).ext`, ... that is free.
+async fn free_path(path: &Path) -> PathBuf {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|s| format!(".{}", s.to_string_lossy())).unwrap_or_default();
+    let mut candidate = path.to_path_buf();
+    let mut index = 1;
+    // A name whose lock file exists is being downloaded by someone else.
+    while exists(&candidate).await || exists(&lock_path(&candidate)).await {
+        candidate = directory.join(format!("{} ({}){}", stem, index, extension));
+        index += 1;
+    }
+    candidate
+}
+
+/// Whether the file at `path` is the one the download would fetch: by hash when the
+/// download is verified, otherwise by the size the server reported.
+async fn matches_remote(config: &DownloadConfiguration, path: &Path, remote_length: Option<u64>) -> bool {
+    if config.file_verify != FileVerify::None {
+        return file_verify::file_validate(&config.file_verify, path).await.is_ok();
+    }
+    match (remote_length, fs::metadata(path).await) {
+        (Some(length), Ok(metadata)) => length > 0 && metadata.len() == length,
+        _ => false,
+    }
+}
+
+async fn same_content(a: &Path, b: &Path) -> bool {
+    let (Ok(a_metadata), Ok(b_metadata)) = (fs::metadata(a).await, fs::metadata(b).await) else {
+        return false;
+    };
+    if a_metadata.len() != b_metadata.len() {
+        return false;
+    }
+    match (file_verify::calculate_file_xxhash(a, 0).await, file_verify::calculate_file_xxhash(b, 0).await) {
+        (Ok(a_hash), Ok(b_hash)) => a_hash == b_hash,
+        _ => false,
+    }
+}
+
+async fn exists(path: &Path) -> bool {
+    fs::try_exists(path).await.unwrap_or(false)
+}
+
+async fn rename(from: &Path, to: &Path) -> crate::error::Result<()> {
+    fs::rename(from, to).await
+        .map_err(|e| DownloadError::FileRename(format!("file rename failed {}", e)))
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.lock", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::download_configuration::{DownloadConfiguration, ExistingFilePolicy};
+    use super::*;
+
+    fn config(directory: &Path, policy: ExistingFilePolicy) -> DownloadConfiguration {
+        DownloadConfiguration::new()
+            .set_url("https://example.com/a.txt")
+            .set_file_path(directory.join("a.txt"))
+            .set_existing_file_policy(policy)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_existing_file_policy() {
+        let directory = std::env::temp_dir().join(format!("downloader-rs-existing-{}", std::process::id()));
+        fs::create_dir_all(&directory).await.unwrap();
+        let path = directory.join("a.txt");
+        let temp = directory.join("a.txt.temp");
+        fs::write(&path, b"old").await.unwrap();
+        fs::write(directory.join("a (1).txt"), b"old").await.unwrap();
+
+        let rename = config(&directory, ExistingFilePolicy::Rename);
+        let Ok(Destination::Path(free)) = check(&rename, &path, None).await else {
+            panic!("rename should not skip");
+        };
+        assert_eq!(free, directory.join("a (2).txt"));
+
+        let same = config(&directory, ExistingFilePolicy::SkipIfSame);
+        assert!(matches!(check(&same, &path, Some(3)).await, Ok(Destination::Skip(_))));
+        assert!(matches!(check(&same, &path, Some(4)).await, Ok(Destination::Path(_))));
+        assert!(matches!(check(&same, &path, None).await, Ok(Destination::Path(_))));
+
+        let fail = config(&directory, ExistingFilePolicy::Fail);
+        assert!(matches!(check(&fail, &path, None).await, Err(DownloadError::FileExists(_))));
+
+        fs::write(&temp, b"new").await.unwrap();
+        let backup = config(&directory, ExistingFilePolicy::Backup);
+        assert!(matches!(place(&backup, &temp, &path).await, Ok(Destination::Path(_))));
+        assert_eq!(fs::read(&path).await.unwrap(), b"new");
+        assert_eq!(fs::read(backup_path(&path)).await.unwrap(), b"old");
+
+        fs::write(&temp, b"new").await.unwrap();
+        assert!(matches!(place(&same, &temp, &path).await, Ok(Destination::Skip(_))));
+        assert!(!exists(&temp).await);
+
+        fs::remove_dir_all(&directory).await.unwrap();
+    }
+}
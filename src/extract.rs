@@ -0,0 +1,257 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use crate::error::DownloadError;
+
+/// Format of an archive extracted after download.
+///
+/// `Tar` needs the `tar` cargo feature, `TarGz` and `TarZst` additionally need `gzip` or
+/// `zstd`, and `Zip` needs the `zip` feature.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Detect the format from the extension of `path`.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let name = path.as_ref().file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(ArchiveFormat::TarZst)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the crate was built with the features for this format.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            ArchiveFormat::Tar => cfg!(feature = "tar"),
+            ArchiveFormat::TarGz => cfg!(all(feature = "tar", feature = "gzip")),
+            ArchiveFormat::TarZst => cfg!(all(feature = "tar", feature = "zstd")),
+            ArchiveFormat::Zip => cfg!(feature = "zip"),
+        }
+    }
+}
+
+impl Display for ArchiveFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveFormat::Tar => write!(f, "tar"),
+            ArchiveFormat::TarGz => write!(f, "tar.gz"),
+            ArchiveFormat::TarZst => write!(f, "tar.zst"),
+            ArchiveFormat::Zip => write!(f, "zip"),
+        }
+    }
+}
+
+/// Extract `archive` into `target`, adding the size of every written file to `extracted`.
+///
+/// Only regular files and directories are written, and entries whose path would leave
+/// `target` are skipped. This blocks, so it runs on the blocking thread pool.
+#[allow(unused_variables)]
+pub(crate) fn extract(
+    format: ArchiveFormat,
+    archive: &Path,
+    target: &Path,
+    extracted: &AtomicU64,
+    cancel_token: &CancellationToken) -> crate::error::Result<()> {
+    match format {
+        #[cfg(feature = "tar")]
+        ArchiveFormat::Tar => {
+            extract_tar(open(archive)?, target, extracted, cancel_token)
+        }
+        #[cfg(all(feature = "tar", feature = "gzip"))]
+        ArchiveFormat::TarGz => {
+            extract_tar(flate2::read::MultiGzDecoder::new(open(archive)?), target, extracted, cancel_token)
+        }
+        #[cfg(all(feature = "tar", feature = "zstd"))]
+        ArchiveFormat::TarZst => {
+            let decoder = zstd::stream::read::Decoder::with_buffer(open(archive)?).map_err(error)?;
+            extract_tar(decoder, target, extracted, cancel_token)
+        }
+        #[cfg(feature = "zip")]
+        ArchiveFormat::Zip => {
+            extract_zip(open(archive)?, target, extracted, cancel_token)
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(DownloadError::Extract(format!("{} support is not enabled", format))),
+    }
+}
+
+#[cfg(any(feature = "tar", feature = "zip"))]
+fn open(path: &Path) -> crate::error::Result<std::io::BufReader<std::fs::File>> {
+    match std::fs::File::open(path) {
+        Ok(file) => Ok(std::io::BufReader::with_capacity(64 * 1024, file)),
+        Err(_) => Err(DownloadError::FileOpen),
+    }
+}
+
+#[cfg(any(feature = "tar", feature = "zip"))]
+fn error(e: impl Display) -> DownloadError {
+    DownloadError::Extract(e.to_string())
+}
+
+/// Join the archive entry `name` onto `target`, or `None` if it is absolute or contains
+/// `..` and so could point outside `target`.
+#[cfg(any(feature = "tar", feature = "zip"))]
+fn entry_path(target: &Path, name: &Path) -> Option<std::path::PathBuf> {
+    use std::path::Component;
+    let mut path = target.to_path_buf();
+    let mut empty = true;
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => {
+                path.push(part);
+                empty = false;
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!empty).then_some(path)
+}
+
+#[cfg(any(feature = "tar", feature = "zip"))]
+fn write_file(reader: &mut impl std::io::Read, path: &Path, mode: Option<u32>, extracted: &AtomicU64) -> crate::error::Result<()> {
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(error)?;
+    }
+    let mut file = std::fs::File::create(path).map_err(|_| DownloadError::OpenOrCreateFile)?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer).map_err(error)?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buffer[..n]).map_err(|_| DownloadError::FileWrite)?;
+        extracted.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    // Only the permission bits are kept; setuid, setgid and sticky bits are dropped.
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777));
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+#[cfg(feature = "tar")]
+fn extract_tar(reader: impl std::io::Read, target: &Path, extracted: &AtomicU64, cancel_token: &CancellationToken) -> crate::error::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(error)? {
+        if cancel_token.is_cancelled() {
+            return Ok(());
+        }
+        let mut entry = entry.map_err(error)?;
+        let name = entry.path().map_err(error)?.into_owned();
+        let Some(path) = entry_path(target, &name) else {
+            tracing::warn!(entry = %name.display(), "skipping archive entry outside the target directory");
+            continue;
+        };
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            std::fs::create_dir_all(&path).map_err(error)?;
+        } else if entry_type.is_file() {
+            let mode = entry.header().mode().ok();
+            write_file(&mut entry, &path, mode, extracted)?;
+        } else if !entry_type.is_pax_global_extensions() && !entry_type.is_pax_local_extensions() {
+            tracing::warn!(entry = %name.display(), "skipping archive entry that is not a file or directory");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "zip")]
+fn extract_zip(
+    reader: impl std::io::Read + std::io::Seek,
+    target: &Path,
+    extracted: &AtomicU64,
+    cancel_token: &CancellationToken) -> crate::error::Result<()> {
+    let mut archive = zip::ZipArchive::new(reader).map_err(error)?;
+    for i in 0..archive.len() {
+        if cancel_token.is_cancelled() {
+            return Ok(());
+        }
+        let mut entry = archive.by_index(i).map_err(error)?;
+        let Some(path) = entry.enclosed_name().and_then(|name| entry_path(target, &name)) else {
+            tracing::warn!(entry = entry.name(), "skipping archive entry outside the target directory");
+            continue;
+        };
+        if entry.is_symlink() {
+            tracing::warn!(entry = entry.name(), "skipping archive entry that is not a file or directory");
+        } else if entry.is_dir() {
+            std::fs::create_dir_all(&path).map_err(error)?;
+        } else {
+            let mode = entry.unix_mode();
+            write_file(&mut entry, &path, mode, extracted)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(ArchiveFormat::from_path("/tmp/a.TAR.GZ"), Some(ArchiveFormat::TarGz));
+        assert_eq!(ArchiveFormat::from_path("a.tzst"), Some(ArchiveFormat::TarZst));
+        assert_eq!(ArchiveFormat::from_path("a.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(ArchiveFormat::from_path("a.gz"), None);
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn test_extract_tar() {
+        use std::sync::atomic::Ordering;
+
+        let directory = std::env::temp_dir().join(format!("downloader-rs-extract-{}", std::process::id()));
+        let target = directory.join("out");
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |name: &[u8], entry_type: tar::EntryType, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+            header.set_entry_type(entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        };
+        append(b"dir/", tar::EntryType::Directory, b"");
+        append(b"dir/a.txt", tar::EntryType::Regular, b"hello");
+        append(b"../evil.txt", tar::EntryType::Regular, b"evil");
+        append(b"link", tar::EntryType::Symlink, b"");
+        let archive = directory.join("a.tar");
+        std::fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        let extracted = AtomicU64::new(0);
+        extract(ArchiveFormat::Tar, &archive, &target, &extracted, &CancellationToken::new()).unwrap();
+        assert_eq!(std::fs::read(target.join("dir/a.txt")).unwrap(), b"hello");
+        assert!(!directory.join("evil.txt").exists());
+        assert!(!target.join("link").exists());
+        assert_eq!(extracted.load(Ordering::Relaxed), 5);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::error::DownloadError;
+
+/// Advisory lock on a download destination, held through a `.lock` file next to it.
+///
+/// The lock is taken with the operating system's file locking, so a lock left behind
+/// by a crashed process is released with it; the stale `.lock` file is then reclaimed by
+/// the next download. The lock file is removed when the guard is dropped.
+pub struct FileLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl FileLock {
+    /// Try to lock `path`, failing with [`DownloadError::FileLocked`] if another
+    /// downloader holds it.
+    pub fn acquire(path: impl AsRef<Path>) -> crate::error::Result<FileLock> {
+        let path = lock_file_path(path.as_ref());
+        if let Some(parent) = path.parent() {
+            if parent.symlink_metadata().is_err() {
+                let _ = fs::create_dir_all(parent);
+            }
+        }
+
+        loop {
+            let mut file = match OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&path) {
+                Ok(file) => file,
+                Err(_) => return Err(DownloadError::OpenOrCreateFile),
+            };
+
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    return Err(DownloadError::FileLocked(path.display().to_string()));
+                }
+                Err(TryLockError::Error(_)) => return Err(DownloadError::OpenOrCreateFile),
+            }
+
+            // The previous holder may have removed the lock file between our open and
+            // lock; in that case we locked an orphaned file and have to start over.
+            if !is_same_file(&file, &path) {
+                continue;
+            }
+
+            let mut previous_owner = String::new();
+            let _ = file.read_to_string(&mut previous_owner);
+            if !previous_owner.trim().is_empty() {
+                tracing::info!(lock = %path.display(), pid = previous_owner.trim(), "reclaiming stale lock file");
+            }
+
+            let written = file.set_len(0)
+                .and_then(|_| file.seek(SeekFrom::Start(0)))
+                .and_then(|_| write!(file, "{}", std::process::id()))
+                .and_then(|_| file.flush());
+            if written.is_err() {
+                return Err(DownloadError::FileWrite);
+            }
+
+            return Ok(FileLock {
+                path,
+                _file: file,
+            });
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Remove the file while still holding the lock, so a waiting process can never
+        // lock a file that is about to disappear. On platforms that refuse to delete an
+        // open file the unlocked lock file stays behind and is reclaimed later.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Build the lock file path for a destination, e.g. `/tmp/file.bin.lock`.
+fn lock_file_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.lock", path.display()))
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let path = std::env::temp_dir().join(format!("downloader-rs-lock-{}.bin", std::process::id()));
+        let lock = FileLock::acquire(&path).unwrap();
+        assert!(lock_file_path(&path).exists());
+        assert!(matches!(FileLock::acquire(&path), Err(DownloadError::FileLocked(_))));
+
+        drop(lock);
+        assert!(!lock_file_path(&path).exists());
+
+        // A leftover lock file that nobody holds is reclaimed.
+        fs::write(lock_file_path(&path), "999999").unwrap();
+        let lock = FileLock::acquire(&path).unwrap();
+        assert_eq!(fs::read_to_string(lock_file_path(&path)).unwrap(), std::process::id().to_string());
+        drop(lock);
+    }
+}
@@ -0,0 +1,203 @@
+use reqwest::Url;
+
+/// Longest file name, in bytes, accepted by common file systems.
+const FILE_SYSTEM_NAME_LENGTH: usize = 255;
+
+/// Room left for what is appended to a sanitized name: a ` (n)` suffix and the side files
+/// such as `.temp`, `.chunkN`, `.metadata`, `.validators`, `.lock` and `.bak`.
+const SUFFIX_RESERVE: usize = 32;
+
+/// Longest name [`sanitize`] returns.
+const MAX_FILE_NAME_LENGTH: usize = FILE_SYSTEM_NAME_LENGTH - SUFFIX_RESERVE;
+
+/// Extract the file name from a `Content-Disposition` header value, preferring the
+/// RFC 5987 `filename*` parameter over `filename`. The name is not sanitized.
+pub fn from_content_disposition(value: &str) -> Option<String> {
+    let mut file_name = None;
+    for parameter in split_parameters(value).into_iter().skip(1) {
+        let Some((name, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                if let Some(decoded) = decode_extended_value(value.trim()) {
+                    return Some(decoded);
+                }
+            }
+            "filename" => {
+                file_name = Some(unquote(value.trim()));
+            }
+            _ => {}
+        }
+    }
+    file_name
+}
+
+/// Extract the file name from the last path segment of `url`.
+pub fn from_url(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let segment = url.path_segments()?.next_back()?;
+    let bytes = percent_decode(segment)?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Reduce an untrusted name to a single path component that is safe to create in the
+/// destination directory, or `None` if nothing usable remains. Long names are cut short
+/// enough that the crate's suffixes still fit.
+pub fn sanitize(name: &str) -> Option<String> {
+    // Only the last component counts, so "../../etc/passwd" becomes "passwd".
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_end_matches(['.', ' ']);
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+
+    let mut name = name.to_string();
+    if is_reserved(&name) {
+        name.insert(0, '_');
+    }
+    if name.len() > MAX_FILE_NAME_LENGTH {
+        let mut end = MAX_FILE_NAME_LENGTH;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    Some(name)
+}
+
+/// Device names that cannot be used as file names on Windows.
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().to_ascii_uppercase();
+    matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || ((stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.len() == 4
+            && stem.as_bytes()[3].is_ascii_digit())
+}
+
+/// Split a header value on `;`, ignoring separators inside quoted strings.
+fn split_parameters(value: &str) -> Vec<&str> {
+    let mut parameters = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                parameters.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parameters.push(&value[start..]);
+    parameters
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut result = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => result.extend(chars.next()),
+                    c => result.push(c),
+                }
+            }
+            result
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Decode an RFC 5987 `charset'language'value` extended parameter value.
+fn decode_extended_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let bytes = percent_decode(parts.next()?)?;
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" => String::from_utf8(bytes).ok(),
+        "iso-8859-1" => Some(bytes.into_iter().map(char::from).collect()),
+        _ => None,
+    }
+}
+
+fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(from_content_disposition("attachment; filename=\"a; b.zip\"").as_deref(), Some("a; b.zip"));
+        assert_eq!(from_content_disposition("attachment; filename=plain.txt").as_deref(), Some("plain.txt"));
+        assert_eq!(
+            from_content_disposition("attachment; filename=\"fallback.txt\"; filename*=UTF-8''na%C3%AFve%20file.txt").as_deref(),
+            Some("naïve file.txt"));
+        assert_eq!(from_content_disposition("attachment; filename*=iso-8859-1'en'%E9t%E9.txt").as_deref(), Some("été.txt"));
+        assert_eq!(from_content_disposition("inline"), None);
+        assert_eq!(from_url("https://example.com/files/my%20file.bin?sig=1").as_deref(), Some("my file.bin"));
+        assert_eq!(from_url("https://example.com/").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize("..\\..\\boot.ini").as_deref(), Some("boot.ini"));
+        assert_eq!(sanitize("a:b?.txt ").as_deref(), Some("a_b_.txt"));
+        assert_eq!(sanitize("NUL.txt").as_deref(), Some("_NUL.txt"));
+        assert_eq!(sanitize(".."), None);
+        assert_eq!(sanitize("dir/"), None);
+        assert_eq!(sanitize(&"é".repeat(200)).map(|name| name.len()), Some(222));
+    }
+
+    #[test]
+    fn test_long_content_disposition_name() {
+        let header = format!("attachment; filename=\"{}.bin\"", "a".repeat(300));
+        let name = sanitize(&from_content_disposition(&header).unwrap()).unwrap();
+        assert_eq!(name.len(), MAX_FILE_NAME_LENGTH);
+
+        // Every file the crate derives from the name can still be created.
+        let root = std::env::temp_dir().join(format!("downloader-rs-long-name-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        for suffix in [" (99)", ".temp", ".chunk9999", ".metadata", ".validators", ".lock", ".bak", ".decoded"] {
+            let path = root.join(format!("{}{}", name, suffix));
+            std::fs::write(&path, b"").unwrap();
+        }
+        let numbered = root.join(format!("{} (99).chunk9999.metadata", name));
+        std::fs::write(&numbered, b"").unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -4,10 +4,18 @@
 //!
 //! Features:
 //! - Chunked & range-based downloads
-//! - Global rate limiting (token-bucket)
-//! - In-memory download mode
+//! - Per-download, per-host and service-wide rate limiting (token-bucket)
+//! - Time-of-day bandwidth schedules that throttle or pause the service
+//! - In-memory download mode with an optional memory budget, and pluggable download sinks
+//! - Streaming the body to the caller as it arrives
 //! - File verification (xxHash)
 //! - Parallel download service with configurable concurrency
+//! - Persistent download queue that survives process restarts
+//! - Content-addressed download cache with LRU eviction
+//! - Optional gzip / zstd / brotli / xz decompression (cargo features)
+//! - Optional tar / tar.gz / tar.zst / zip extraction (cargo features)
+//! - Binary patch downloads in bsdiff or zstd `--patch-from` format (cargo features)
+//! - zsync-style delta downloads that reuse matching blocks of an older local file
 
 mod download_task;
 mod stream;
@@ -19,11 +27,30 @@ mod chunk_range;
 mod download_tracker;
 mod download_sender;
 mod download_receiver;
+mod file_lock;
+mod request;
+mod download_url;
+mod file_name;
+mod existing_file;
 pub mod verify;
 pub mod error;
 pub mod rate_limiter;
 pub mod download_status;
+pub mod download_outcome;
 pub mod download_configuration;
 pub mod download_service;
 pub mod downloader;
-pub mod download_operation;
\ No newline at end of file
+pub mod download_operation;
+pub mod queue_store;
+pub mod download_cache;
+pub mod credentials;
+pub mod service_configuration;
+pub mod url_provider;
+pub mod compression;
+pub mod extract;
+pub mod patch;
+pub mod delta;
+pub mod sink;
+pub mod body_stream;
+pub mod memory_budget;
+pub mod bandwidth_schedule;
\ No newline at end of file
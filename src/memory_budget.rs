@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use bytes::Bytes;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::error::DownloadError;
+
+/// The budget is accounted in units of this many bytes, so large payloads fit the
+/// semaphore's `u32` acquisitions.
+const UNIT: u64 = 1024;
+
+static SPILL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Caps the memory held by the in-memory downloads of a
+/// [`DownloadService`](crate::download_service::DownloadService).
+///
+/// Once the server has reported its length, an in-memory download reserves that many
+/// bytes and waits while the budget is exhausted. The reservation is released when the
+/// download fails, or once the [`Payload`] it produced, every clone of it and the
+/// download's operations have been dropped.
+///
+/// With [`set_spill`](MemoryBudget::set_spill), payloads above a threshold and payloads
+/// of unknown length are written to a temporary file instead and do not count against
+/// the budget.
+pub struct MemoryBudget {
+    limit: u64,
+    semaphore: Arc<Semaphore>,
+    spill: Option<(u64, PathBuf)>,
+}
+
+impl MemoryBudget {
+    /// Create a budget of `limit` bytes.
+    pub fn new(limit: u64) -> Self {
+        let permits = (limit.div_ceil(UNIT) as usize).min(Semaphore::MAX_PERMITS);
+        Self {
+            limit,
+            semaphore: Arc::new(Semaphore::new(permits)),
+            spill: None,
+        }
+    }
+
+    /// Write payloads larger than `threshold` bytes to temporary files in `directory`.
+    pub fn set_spill(mut self, threshold: u64, directory: impl AsRef<Path>) -> Self {
+        self.spill = Some((threshold, directory.as_ref().to_path_buf()));
+        self
+    }
+
+    /// The budget in bytes.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// The bytes not reserved at the moment.
+    pub fn available(&self) -> u64 {
+        (self.semaphore.available_permits() as u64 * UNIT).min(self.limit)
+    }
+
+    /// Reserve room for a payload of `length` bytes, 0 if unknown, waiting until it is available.
+    pub(crate) async fn reserve(&self, length: u64) -> crate::error::Result<Reservation> {
+        if let Some((threshold, directory)) = &self.spill {
+            if length == 0 || length > *threshold {
+                let _ = tokio::fs::create_dir_all(directory).await;
+                let name = format!("spill-{}-{}", std::process::id(), SPILL_COUNTER.fetch_add(1, Ordering::Relaxed));
+                return Ok(Reservation::Spill(Arc::new(SpilledFile { path: directory.join(name) })));
+            }
+        }
+        if length == 0 {
+            tracing::warn!("in-memory download of unknown length is not counted against the memory budget");
+            return Ok(Reservation::Memory(None));
+        }
+        if length > self.limit {
+            return Err(DownloadError::MemoryBudget(format!("{} bytes exceed the budget of {} bytes", length, self.limit)));
+        }
+        let units = u32::try_from(length.div_ceil(UNIT))
+            .map_err(|_| DownloadError::MemoryBudget(format!("{} bytes cannot be reserved at once", length)))?;
+        if self.semaphore.available_permits() < units as usize {
+            tracing::info!(length, available = self.available(), "waiting for memory budget");
+        }
+        let permit = self.semaphore.clone().acquire_many_owned(units).await
+            .map_err(|_| DownloadError::MemoryBudget("budget closed".to_string()))?;
+        Ok(Reservation::Memory(Some(permit)))
+    }
+}
+
+/// Where an in-memory download is written.
+pub(crate) enum Reservation {
+    /// Into memory, holding the budget's permit if it is budgeted.
+    Memory(Option<OwnedSemaphorePermit>),
+    /// Into a temporary file.
+    Spill(Arc<SpilledFile>),
+}
+
+/// The result of an in-memory download.
+#[derive(Clone)]
+pub enum Payload {
+    Memory(Bytes),
+    /// The payload was spilled to a temporary file by the [`MemoryBudget`].
+    File(Arc<SpilledFile>),
+}
+
+impl Payload {
+    /// The payload's bytes, read from its file if it was spilled.
+    pub fn to_bytes(&self) -> std::io::Result<Bytes> {
+        match self {
+            Payload::Memory(bytes) => Ok(bytes.clone()),
+            Payload::File(file) => std::fs::read(file.path()).map(Bytes::from),
+        }
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::Memory(Bytes::new())
+    }
+}
+
+/// A temporary file holding a spilled payload. It is deleted when dropped.
+pub struct SpilledFile {
+    path: PathBuf,
+}
+
+impl SpilledFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpilledFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Downloaded bytes that return their reservation to the budget when dropped.
+pub(crate) struct Budgeted {
+    pub bytes: Vec<u8>,
+    pub _permit: OwnedSemaphorePermit,
+}
+
+impl AsRef<[u8]> for Budgeted {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reserve() {
+        let directory = std::env::temp_dir().join(format!("downloader-rs-budget-{}", std::process::id()));
+        let budget = MemoryBudget::new(10 * 1024).set_spill(8 * 1024, &directory);
+
+        let Reservation::Memory(Some(first)) = budget.reserve(6 * 1024).await.unwrap() else { panic!() };
+        assert_eq!(budget.available(), 4 * 1024);
+        assert!(matches!(budget.reserve(9 * 1024).await.unwrap(), Reservation::Spill(_)));
+        assert!(matches!(budget.reserve(0).await.unwrap(), Reservation::Spill(_)));
+
+        // The second reservation waits until the first one is released.
+        let waiting = budget.reserve(6 * 1024);
+        tokio::pin!(waiting);
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(20), &mut waiting).await.is_err());
+        let bytes = Bytes::from_owner(Budgeted { bytes: vec![1u8; 10], _permit: first });
+        drop(bytes);
+        assert!(matches!(waiting.await.unwrap(), Reservation::Memory(Some(_))));
+
+        assert!(MemoryBudget::new(1024).reserve(2048).await.is_err());
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
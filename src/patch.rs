@@ -0,0 +1,123 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::error::DownloadError;
+
+/// Format of a binary patch applied to a local base file.
+///
+/// `Bsdiff` needs the `bsdiff` cargo feature and `Zstd` the `zstd` feature.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum PatchFormat {
+    /// A classic `BSDIFF40` patch, as produced by `bsdiff`.
+    Bsdiff,
+    /// A zstd frame compressed with the base file as reference, as produced by
+    /// `zstd --patch-from=<base>`.
+    Zstd,
+}
+
+impl PatchFormat {
+    /// Returns `true` if the crate was built with the feature for this format.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            PatchFormat::Bsdiff => cfg!(feature = "bsdiff"),
+            PatchFormat::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+}
+
+impl Display for PatchFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchFormat::Bsdiff => write!(f, "bsdiff"),
+            PatchFormat::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Apply the `patch` file to `base`, writing the result to `target` and returning its size.
+///
+/// Both formats need the whole base file in memory. This blocks, so it runs on the
+/// blocking thread pool.
+#[allow(unused_variables)]
+pub(crate) fn apply(format: PatchFormat, base: &Path, patch: &Path, target: &Path) -> crate::error::Result<u64> {
+    match format {
+        #[cfg(feature = "bsdiff")]
+        PatchFormat::Bsdiff => {
+            let base = read(base)?;
+            let patch = read(patch)?;
+            let patcher = qbsdiff::Bspatch::new(&patch).map_err(error)?;
+            let mut output = std::io::BufWriter::new(create(target)?);
+            let size = patcher.apply(&base, &mut output).map_err(error)?;
+            std::io::Write::flush(&mut output).map_err(|_| DownloadError::FileFlush)?;
+            Ok(size)
+        }
+        #[cfg(feature = "zstd")]
+        PatchFormat::Zstd => {
+            let base = read(base)?;
+            let patch = std::fs::File::open(patch).map_err(|_| DownloadError::FileOpen)?;
+            let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(std::io::BufReader::new(patch), &base)
+                .map_err(error)?;
+            // Patches of large files are made with `--long`, which needs a larger window.
+            decoder.window_log_max(31).map_err(error)?;
+            let mut output = std::io::BufWriter::new(create(target)?);
+            let size = std::io::copy(&mut decoder, &mut output).map_err(error)?;
+            std::io::Write::flush(&mut output).map_err(|_| DownloadError::FileFlush)?;
+            Ok(size)
+        }
+        #[allow(unreachable_patterns)]
+        _ => {
+            tracing::error!(%format, "patch support is not enabled");
+            Err(DownloadError::Patch)
+        }
+    }
+}
+
+#[cfg(any(feature = "bsdiff", feature = "zstd"))]
+fn read(path: &Path) -> crate::error::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|_| DownloadError::FileOpen)
+}
+
+#[cfg(any(feature = "bsdiff", feature = "zstd"))]
+fn create(path: &Path) -> crate::error::Result<std::fs::File> {
+    std::fs::File::create(path).map_err(|_| DownloadError::OpenOrCreateFile)
+}
+
+#[cfg(any(feature = "bsdiff", feature = "zstd"))]
+fn error(e: impl Display) -> DownloadError {
+    tracing::error!(error = %e, "failed to apply patch");
+    DownloadError::Patch
+}
+
+#[cfg(all(test, feature = "bsdiff", feature = "zstd"))]
+mod tests {
+    use std::io::Write;
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let directory = std::env::temp_dir().join(format!("downloader-rs-patch-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let base: Vec<u8> = (0..100_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+        let mut new = base.clone();
+        new[1000..1010].copy_from_slice(b"0123456789");
+        new.extend_from_slice(b"appended");
+        std::fs::write(directory.join("base"), &base).unwrap();
+
+        let mut bsdiff_patch = Vec::new();
+        qbsdiff::Bsdiff::new(&base, &new).compare(&mut bsdiff_patch).unwrap();
+        std::fs::write(directory.join("patch.bsdiff"), &bsdiff_patch).unwrap();
+        apply(PatchFormat::Bsdiff, &directory.join("base"), &directory.join("patch.bsdiff"), &directory.join("new")).unwrap();
+        assert_eq!(std::fs::read(directory.join("new")).unwrap(), new);
+
+        let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(Vec::new(), 3, &base).unwrap();
+        encoder.write_all(&new).unwrap();
+        std::fs::write(directory.join("patch.zst"), encoder.finish().unwrap()).unwrap();
+        apply(PatchFormat::Zstd, &directory.join("base"), &directory.join("patch.zst"), &directory.join("new")).unwrap();
+        assert_eq!(std::fs::read(directory.join("new")).unwrap(), new);
+
+        std::fs::write(directory.join("patch.bad"), b"garbage").unwrap();
+        assert!(apply(PatchFormat::Bsdiff, &directory.join("base"), &directory.join("patch.bad"), &directory.join("new")).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,72 +1,303 @@
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use tokio::time::{sleep, Duration, Instant};
 
-/// A token-bucket rate limiter shared across all download tasks.
+/// Largest amount a [`RateLimiterChain`] takes from its limiters at once, so downloads
+/// sharing a limiter take turns instead of one draining it with a large buffer.
+const SLICE: u64 = 16 * 1024;
+
+/// Bounds of a single wait for tokens. Waits are re-evaluated at least this often so a
+/// changed limit takes effect promptly.
+const MIN_WAIT: Duration = Duration::from_millis(1);
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token-bucket rate limiter. It may limit the chunks of a single download or be
+/// shared by many downloads, such as all downloads from a host.
 /// When `bytes_per_second` is 0, no rate limiting is applied.
+///
+/// The bucket holds up to `burst` bytes, one second's worth by default, and starts full.
+/// Acquisitions are served in the order they arrive; one larger than the bucket takes
+/// the tokens as they are refilled instead of waiting for all of them at once.
+///
+/// The limit can be changed with [`set_bytes_per_second`](RateLimiter::set_bytes_per_second)
+/// while downloads are using it, and held at zero with [`set_paused`](RateLimiter::set_paused).
 pub struct RateLimiter {
-    bytes_per_second: u64,
-    tokens: AtomicU64,
-    last_refill: std::sync::Mutex<Instant>,
+    bytes_per_second: AtomicU64,
+    /// Lets no bytes through while set, whatever the limit.
+    paused: AtomicBool,
+    /// The bucket's capacity, 0 for one second's worth.
+    burst: AtomicU64,
+    bucket: std::sync::Mutex<Bucket>,
+    /// Held by the acquisition being served. Tokio's mutex queues waiters in FIFO order.
+    queue: tokio::sync::Mutex<()>,
 }
 
 impl RateLimiter {
     pub fn new(bytes_per_second: u64) -> Arc<Self> {
-        Arc::new(Self {
-            bytes_per_second,
-            tokens: AtomicU64::new(bytes_per_second),
-            last_refill: std::sync::Mutex::new(Instant::now()),
-        })
+        Self::with_burst(bytes_per_second, 0)
+    }
+
+    /// Create a limiter whose bucket holds `burst` bytes, 0 for one second's worth.
+    pub fn with_burst(bytes_per_second: u64, burst: u64) -> Arc<Self> {
+        let limiter = Self {
+            bytes_per_second: AtomicU64::new(bytes_per_second),
+            paused: AtomicBool::new(false),
+            burst: AtomicU64::new(burst),
+            bucket: std::sync::Mutex::new(Bucket { tokens: 0.0, last_refill: Instant::now() }),
+            queue: tokio::sync::Mutex::new(()),
+        };
+        limiter.bucket.lock().unwrap().tokens = limiter.capacity(bytes_per_second);
+        Arc::new(limiter)
+    }
+
+    /// The current limit, 0 if unlimited.
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second.load(Ordering::Relaxed)
+    }
+
+    /// Change the limit, 0 for unlimited. Acquisitions use the new limit from their next
+    /// refill on, including those already waiting.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        self.bytes_per_second.store(bytes_per_second, Ordering::Relaxed);
+    }
+
+    /// The most bytes that can be acquired without waiting after a pause.
+    pub fn burst(&self) -> u64 {
+        self.capacity(self.bytes_per_second()) as u64
+    }
+
+    /// Change the bucket's capacity, 0 for one second's worth.
+    pub fn set_burst(&self, burst: u64) {
+        self.burst.store(burst, Ordering::Relaxed);
+    }
+
+    /// Stop letting bytes through until unpaused. Acquisitions already waiting wait on.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
     }
 
     /// Returns true if rate limiting is disabled (unlimited speed).
     pub fn is_unlimited(&self) -> bool {
-        self.bytes_per_second == 0
+        self.bytes_per_second() == 0 && !self.is_paused()
     }
 
-    /// Consume `amount` bytes from the bucket. If not enough tokens,
-    /// sleep until enough have been refilled.
+    /// Consume `amount` bytes from the bucket, waiting behind earlier acquisitions and
+    /// then until enough tokens have been refilled.
     pub async fn acquire(&self, amount: u64) {
-        if self.is_unlimited() {
+        if amount == 0 || self.is_unlimited() {
             return;
         }
 
+        let _turn = self.queue.lock().await;
+        let mut remaining = amount as f64;
         loop {
-            // Refill tokens based on elapsed time
-            self.refill();
-
-            let current = self.tokens.load(Ordering::Relaxed);
-            if current >= amount {
-                // Try to consume tokens
-                match self.tokens.compare_exchange(
-                    current,
-                    current - amount,
-                    Ordering::Relaxed,
-                    Ordering::Relaxed,
-                ) {
-                    Ok(_) => return,
-                    Err(_) => continue, // lost race, retry
-                }
-            } else {
-                // Not enough tokens, sleep a short duration then retry
-                let needed = amount - current;
-                let wait_secs = needed as f64 / self.bytes_per_second as f64;
-                let wait = Duration::from_secs_f64(wait_secs.min(0.1)); // cap wait at 100ms
-                sleep(wait).await;
+            if self.is_paused() {
+                sleep(MAX_WAIT).await;
+                continue;
             }
+            let bytes_per_second = self.bytes_per_second();
+            if bytes_per_second == 0 {
+                return;
+            }
+
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                self.refill(&mut bucket, bytes_per_second);
+                let taken = bucket.tokens.min(remaining);
+                bucket.tokens -= taken;
+                remaining -= taken;
+                if remaining <= 0.0 {
+                    return;
+                }
+                // Wait for the rest, or for a full bucket if the rest does not fit in one.
+                let needed = remaining.min(self.capacity(bytes_per_second));
+                Duration::from_secs_f64(needed / bytes_per_second as f64)
+            };
+            sleep(wait.clamp(MIN_WAIT, MAX_WAIT)).await;
+        }
+    }
+
+    fn capacity(&self, bytes_per_second: u64) -> f64 {
+        match self.burst.load(Ordering::Relaxed) {
+            0 => bytes_per_second as f64,
+            burst => burst as f64,
         }
     }
 
-    fn refill(&self) {
-        let mut last = self.last_refill.lock().unwrap();
+    fn refill(&self, bucket: &mut Bucket, bytes_per_second: u64) {
         let now = Instant::now();
-        let elapsed = now.duration_since(*last);
-        let new_tokens = (elapsed.as_secs_f64() * self.bytes_per_second as f64) as u64;
-        if new_tokens > 0 {
-            *last = now;
-            let current = self.tokens.load(Ordering::Relaxed);
-            let capped = (current + new_tokens).min(self.bytes_per_second);
-            self.tokens.store(capped, Ordering::Relaxed);
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.last_refill = now;
+        bucket.tokens = (bucket.tokens + elapsed * bytes_per_second as f64).min(self.capacity(bytes_per_second));
+    }
+}
+
+/// Rate limiters enforced together, such as a download's own limit, its host's limit and
+/// the service's limit. Received bytes are taken from every limiter, so the tightest one
+/// sets the speed.
+#[derive(Clone, Default)]
+pub struct RateLimiterChain {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl RateLimiterChain {
+    pub fn new(limiters: Vec<Arc<RateLimiter>>) -> Self {
+        Self { limiters }
+    }
+
+    /// Returns true if none of the limiters limits the speed at the moment.
+    pub fn is_unlimited(&self) -> bool {
+        self.limiters.iter().all(|limiter| limiter.is_unlimited())
+    }
+
+    /// Consume `amount` bytes from every limiter, in slices so that downloads sharing a
+    /// limiter get a fair share of it.
+    pub async fn acquire(&self, amount: u64) {
+        if self.is_unlimited() {
+            return;
+        }
+        let mut remaining = amount;
+        while remaining > 0 {
+            let part = remaining.min(SLICE);
+            for limiter in &self.limiters {
+                limiter.acquire(part).await;
+            }
+            remaining -= part;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chain_takes_tightest_limit() {
+        let download = RateLimiter::new(0);
+        let service = RateLimiter::new(64 * 1024);
+        let chain = RateLimiterChain::new(vec![download, service.clone()]);
+
+        // The bucket starts full, after that bytes arrive at the service's rate.
+        let start = Instant::now();
+        for _ in 0..5 {
+            chain.acquire(64 * 1024).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(4), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+        // Two downloads sharing the service limiter split its rate.
+        let other = RateLimiterChain::new(vec![RateLimiter::new(1024 * 1024), service]);
+        let start = Instant::now();
+        let first = async { chain.acquire(128 * 1024).await; start.elapsed() };
+        let second = async { other.acquire(128 * 1024).await; start.elapsed() };
+        let (first, second) = tokio::join!(first, second);
+        assert!(first >= Duration::from_millis(3500), "{:?}", first);
+        assert!(second >= Duration::from_millis(3500), "{:?}", second);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_bytes_per_second() {
+        let limiter = RateLimiter::new(1024);
+        limiter.acquire(1024).await;
+
+        // A waiting acquisition picks up the new limit.
+        let start = Instant::now();
+        let waiting = limiter.acquire(1024);
+        tokio::pin!(waiting);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut waiting).await.is_err());
+        limiter.set_bytes_per_second(0);
+        waiting.await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        limiter.set_bytes_per_second(4096);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire(4096).await;
         }
+        assert!(start.elapsed() < Duration::from_secs(3), "{:?}", start.elapsed());
+        assert!(start.elapsed() >= Duration::from_secs(1), "{:?}", start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_paused() {
+        let limiter = RateLimiter::new(0);
+        limiter.set_paused(true);
+        assert!(!limiter.is_unlimited());
+
+        let waiting = limiter.acquire(1);
+        tokio::pin!(waiting);
+        assert!(tokio::time::timeout(Duration::from_secs(10), &mut waiting).await.is_err());
+        limiter.set_paused(false);
+        let start = Instant::now();
+        waiting.await;
+        assert!(start.elapsed() <= MAX_WAIT, "{:?}", start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_larger_than_bucket() {
+        let limiter = RateLimiter::new(1000);
+
+        // 1000 bytes come from the full bucket, the other 4000 are refilled over 4 seconds.
+        let start = Instant::now();
+        limiter.acquire(5000).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(4), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(4100), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_burst() {
+        let limiter = RateLimiter::with_burst(1000, 3000);
+        assert_eq!(limiter.burst(), 3000);
+
+        let start = Instant::now();
+        limiter.acquire(3000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire(1000).await;
+        assert!(start.elapsed() >= Duration::from_secs(1), "{:?}", start.elapsed());
+
+        // After an idle period the bucket holds no more than the burst.
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        limiter.set_burst(0);
+        let start = Instant::now();
+        limiter.acquire(2000).await;
+        assert!(start.elapsed() >= Duration::from_secs(1), "{:?}", start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_waiters_are_served_in_order() {
+        let limiter = RateLimiter::new(1000);
+        limiter.acquire(1000).await;
+
+        // A small acquisition arriving later does not overtake a large one waiting already.
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let large = tokio::spawn({
+            let (limiter, order) = (limiter.clone(), order.clone());
+            async move {
+                limiter.acquire(2000).await;
+                order.lock().unwrap().push("large");
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let small = tokio::spawn({
+            let (limiter, order) = (limiter.clone(), order.clone());
+            async move {
+                limiter.acquire(100).await;
+                order.lock().unwrap().push("small");
+            }
+        });
+        large.await.unwrap();
+        small.await.unwrap();
+        assert_eq!(*order.lock().unwrap(), ["large", "small"]);
     }
 }
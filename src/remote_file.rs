@@ -2,14 +2,28 @@ use std::sync::{Arc};
 use std::time::Duration;
 use chrono::DateTime;
 use reqwest::{Client};
-use reqwest::header::{HeaderMap};
+use reqwest::header::{HeaderMap, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use reqwest::{Method, StatusCode};
+use crate::chunk_metadata::Validators;
 use crate::download_configuration::DownloadConfiguration;
+use crate::download_url::DownloadUrl;
 use crate::error::DownloadError;
+use crate::{file_name, request};
 
 pub struct RemoteFile {
     pub total_length: u64,
     pub support_range_download: bool,
     pub last_modified_time: i64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// The server answered a conditional request with `304 Not Modified`.
+    pub not_modified: bool,
+    /// The URL the request ended up at after following redirects.
+    pub final_url: String,
+    /// Every URL redirected to while probing, in order.
+    pub redirects: Vec<String>,
+    /// The unsanitized file name from the `Content-Disposition` header.
+    pub file_name: Option<String>,
 }
 
 impl RemoteFile {
@@ -17,6 +31,8 @@ impl RemoteFile {
         let mut total_length = 0u64;
         let mut support_range_download = false;
         let mut last_modified_time = 0i64;
+        let mut etag = None;
+        let mut last_modified = None;
         if let Some(value) = head_map.get("accept-ranges") {
             support_range_download = value.as_bytes().eq(b"bytes");
         }
@@ -27,30 +43,70 @@ impl RemoteFile {
                 }
             }
         }
-        if let Some(last_modified) = head_map.get("last-modified") {
-            if let Ok(last_modified_str) = last_modified.to_str() {
+        if let Some(value) = head_map.get("last-modified") {
+            if let Ok(last_modified_str) = value.to_str() {
                 if let Ok(last_modified_datetime) = DateTime::parse_from_rfc2822(last_modified_str) {
                     last_modified_time = last_modified_datetime.timestamp();
                 }
+                last_modified = Some(last_modified_str.to_string());
             }
         }
 
+        if let Some(value) = head_map.get("etag") {
+            if let Ok(value) = value.to_str() {
+                etag = Some(value.to_string());
+            }
+        }
+
+        let file_name = head_map.get("content-disposition")
+            .and_then(|value| value.to_str().ok())
+            .and_then(file_name::from_content_disposition);
+
         Self {
             total_length,
             support_range_download,
             last_modified_time,
+            etag,
+            last_modified,
+            not_modified: false,
+            final_url: String::new(),
+            redirects: Vec::new(),
+            file_name,
+        }
+    }
+
+    pub fn validators(&self) -> Validators {
+        Validators {
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
         }
     }
 }
 
-pub async fn head(client: &Arc<Client>, config: &Arc<DownloadConfiguration>) -> crate::error::Result<RemoteFile> {
+/// Send the HEAD request for `config`. With `validators`, the request is conditional
+/// and a `304 Not Modified` answer is reported through [`RemoteFile::not_modified`].
+pub async fn head(
+    client: &Arc<Client>,
+    config: &Arc<DownloadConfiguration>,
+    download_url: &DownloadUrl,
+    validators: Option<&Validators>) -> crate::error::Result<RemoteFile> {
     let retry_count_limit = config.retry_times_on_failure;
     let mut retry_count = 0;
+    let mut refresh_credentials = false;
 
     'r: loop {
-        let request = client.head(config.url());
+        let url = download_url.current().await?;
+        let mut headers = HeaderMap::new();
+        if let Some(validators) = validators {
+            if let Some(etag) = validators.etag.as_deref().and_then(|etag| HeaderValue::from_str(etag).ok()) {
+                headers.insert(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = validators.last_modified.as_deref().and_then(|value| HeaderValue::from_str(value).ok()) {
+                headers.insert(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
 
-        let send_future = request.send();
+        let send_future = request::send(client, config, Method::HEAD, &url, headers, refresh_credentials);
         let result = if config.timeout > 0 {
             tokio::time::timeout(
                 Duration::from_secs(config.timeout),
@@ -61,18 +117,24 @@ pub async fn head(client: &Arc<Client>, config: &Arc<DownloadConfiguration>) ->
         };
 
         // Timeout or request error → retry
-        let response = match result {
-            Ok(Ok(resp)) => resp,
-            _ => {
+        let followed = match result {
+            Ok(Ok(followed)) => followed,
+            Ok(Err(DownloadError::Request)) | Err(_) => {
                 if retry_count >= retry_count_limit {
                     return Err(DownloadError::Head);
                 }
                 retry_count += 1;
                 continue 'r;
             }
+            Ok(Err(e)) => return Err(e),
         };
+        let response = followed.response;
 
         if let Err(e) = response.error_for_status_ref() {
+            refresh_credentials = request::is_auth_rejected(response.status());
+            if refresh_credentials {
+                download_url.refresh(&url).await?;
+            }
             if retry_count >= retry_count_limit {
                 if let Some(status_code) = e.status() {
                     return Err(DownloadError::Response(e.url().as_ref().unwrap().to_string(), status_code.into()));
@@ -84,6 +146,10 @@ pub async fn head(client: &Arc<Client>, config: &Arc<DownloadConfiguration>) ->
         }
 
         let headers = response.headers();
-        return Ok(RemoteFile::new(headers));
+        let mut remote_file = RemoteFile::new(headers);
+        remote_file.not_modified = response.status() == StatusCode::NOT_MODIFIED;
+        remote_file.final_url = response.url().to_string();
+        remote_file.redirects = followed.redirects;
+        return Ok(remote_file);
     }
 }
\ No newline at end of file
@@ -0,0 +1,288 @@
+use reqwest::header::{HeaderMap, AUTHORIZATION, COOKIE, LOCATION, PROXY_AUTHORIZATION, USER_AGENT};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode, Url};
+use crate::credentials::Credentials;
+use crate::download_configuration::DownloadConfiguration;
+use crate::error::DownloadError;
+
+/// A response together with the redirects that were followed to reach it.
+pub struct Followed {
+    pub response: Response,
+    /// Every URL redirected to, in order. The last one is the URL of `response`.
+    pub redirects: Vec<String>,
+}
+
+/// Send a request for the download and follow redirects up to the configured limit.
+///
+/// Redirects are followed here rather than by the client so the chain can be reported
+/// and limited per download. Headers, cookies and credentials are only sent to the
+/// host of the configured URL; requests to other hosts, such as a CDN the download was
+/// redirected to, carry the remaining headers.
+/// Fails with [`DownloadError::Request`] if the request could not be sent.
+pub async fn send(
+    client: &Client,
+    config: &DownloadConfiguration,
+    mut method: Method,
+    url: &str,
+    headers: HeaderMap,
+    refresh_credentials: bool) -> crate::error::Result<Followed> {
+    let mut current = Url::parse(url).map_err(|_| DownloadError::Request)?;
+    let trusted_host = Url::parse(config.url()).ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+    let mut redirects = Vec::new();
+
+    loop {
+        let mut request = client.request(method.clone(), current.clone());
+        request = match current.host_str() == trusted_host.as_deref() {
+            true => apply(config, request, refresh_credentials).await?,
+            false => apply_public(config, request),
+        };
+        let response = request.headers(headers.clone()).send().await.map_err(|_| DownloadError::Request)?;
+
+        // A client that follows redirects itself only shows where it ended up.
+        if response.url() != &current {
+            redirects.push(response.url().to_string());
+        }
+
+        let status = response.status();
+        let location = response.headers().get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| response.url().join(location).ok());
+        let next = match (status.is_redirection() && status != StatusCode::NOT_MODIFIED, location) {
+            (true, Some(next)) => next,
+            _ => return Ok(Followed { response, redirects }),
+        };
+
+        if redirects.len() >= config.max_redirects {
+            return Err(DownloadError::TooManyRedirects(url.to_string()));
+        }
+        if status == StatusCode::SEE_OTHER && method != Method::HEAD {
+            method = Method::GET;
+        }
+        tracing::debug!(status = status.as_u16(), location = %next, "following redirect");
+        redirects.push(next.to_string());
+        current = next;
+    }
+}
+
+/// Add the headers, cookies and credentials configured for the download to `request`.
+///
+/// `refresh_credentials` asks the credential provider for new credentials because the
+/// previous request was rejected.
+pub async fn apply(
+    config: &DownloadConfiguration,
+    mut request: RequestBuilder,
+    refresh_credentials: bool) -> crate::error::Result<RequestBuilder> {
+    request = request.headers(config.headers.clone());
+
+    if let Some(user_agent) = &config.user_agent {
+        request = request.header(USER_AGENT, user_agent);
+    }
+
+    if !config.cookies.is_empty() {
+        let cookie = config.cookies.iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        request = request.header(COOKIE, cookie);
+    }
+
+    let credentials = match &config.credential_provider {
+        Some(provider) => Some(provider.credentials(refresh_credentials).await?),
+        None => config.credentials.clone(),
+    };
+    request = match credentials {
+        Some(Credentials::Basic { username, password }) => request.basic_auth(username, password),
+        Some(Credentials::Bearer(token)) => request.bearer_auth(token),
+        None => request,
+    };
+
+    Ok(request)
+}
+
+/// Add the configured headers except those that carry credentials.
+fn apply_public(config: &DownloadConfiguration, mut request: RequestBuilder) -> RequestBuilder {
+    let mut headers = config.headers.clone();
+    headers.remove(AUTHORIZATION);
+    headers.remove(PROXY_AUTHORIZATION);
+    headers.remove(COOKIE);
+    request = request.headers(headers);
+    if let Some(user_agent) = &config.user_agent {
+        request = request.header(USER_AGENT, user_agent);
+    }
+    request
+}
+
+/// Returns `true` if the server rejected the credentials of a request.
+pub fn is_auth_rejected(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use futures::future::BoxFuture;
+    use parking_lot::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::credentials::CredentialProvider;
+    use crate::download_service::DownloadService;
+    use crate::download_status::DownloadStatus;
+    use crate::service_configuration::ServiceConfiguration;
+    use super::*;
+
+    /// Serve `/hop/N` as a redirect to `/hop/N-1` and `/hop/0` as the file, recording
+    /// the request heads. `/elsewhere/HOST` redirects to `/hop/0` on `HOST`, and `/auth`
+    /// answers `401 Unauthorized` unless the bearer token is `fresh`.
+    async fn serve(requests: Arc<Mutex<Vec<String>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    let mut buffer = vec![0u8; 4096];
+                    let n = socket.read(&mut buffer).await.unwrap();
+                    let head = String::from_utf8_lossy(&buffer[..n]).to_string();
+                    let path = head.split_whitespace().nth(1).unwrap().to_string();
+                    let authorized = head.contains("authorization: Bearer fresh");
+                    requests.lock().push(head);
+                    let ok = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok".to_string();
+                    let redirect = |location: String| {
+                        format!("HTTP/1.1 302 Found\r\nlocation: {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", location)
+                    };
+                    let response = if let Some(host) = path.strip_prefix("/elsewhere/") {
+                        redirect(format!("http://{}/hop/0", host))
+                    } else if path == "/auth" {
+                        match authorized {
+                            true => ok,
+                            false => "HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string(),
+                        }
+                    } else {
+                        match path.trim_start_matches("/hop/").parse::<u32>().unwrap() {
+                            0 => ok,
+                            hop => redirect(format!("/hop/{}", hop - 1)),
+                        }
+                    };
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn test_follow_redirects() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let base = serve(requests.clone()).await;
+        let client = ServiceConfiguration::default().build_client().unwrap();
+        let config = DownloadConfiguration::new()
+            .set_url(&format!("{}/hop/2", base))
+            .set_download_in_memory(true)
+            .set_bearer_auth("secret")
+            .set_max_redirects(2)
+            .build()
+            .unwrap();
+
+        let followed = send(&client, &config, Method::GET, config.url(), HeaderMap::new(), false).await.unwrap();
+        assert_eq!(followed.response.status(), StatusCode::OK);
+        assert_eq!(followed.redirects, vec![format!("{}/hop/1", base), format!("{}/hop/0", base)]);
+        assert!(requests.lock().iter().all(|head| head.contains("authorization: Bearer secret")));
+
+        let config = DownloadConfiguration::new()
+            .set_url(&format!("{}/hop/3", base))
+            .set_download_in_memory(true)
+            .set_max_redirects(2)
+            .build()
+            .unwrap();
+        let result = send(&client, &config, Method::GET, config.url(), HeaderMap::new(), false).await;
+        assert!(matches!(result, Err(DownloadError::TooManyRedirects(_))));
+    }
+
+    #[tokio::test]
+    async fn test_cross_host_redirect_drops_credentials() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let base = serve(requests.clone()).await;
+        let address = base.trim_start_matches("http://").parse().unwrap();
+        let client = ServiceConfiguration::new()
+            .add_resolve_override("cdn.test", address)
+            .build()
+            .unwrap()
+            .build_client()
+            .unwrap();
+        let config = DownloadConfiguration::new()
+            .set_url(&format!("{}/elsewhere/cdn.test:{}", base, address.port()))
+            .set_download_in_memory(true)
+            .set_bearer_auth("secret")
+            .set_cookie("session", "secret")
+            .set_cookie("theme", "dark")
+            .set_header("x-trace", "7")
+            .set_user_agent("agent/1")
+            .build()
+            .unwrap();
+
+        let followed = send(&client, &config, Method::GET, config.url(), HeaderMap::new(), false).await.unwrap();
+        assert_eq!(followed.response.status(), StatusCode::OK);
+        assert_eq!(followed.redirects, vec![format!("http://cdn.test:{}/hop/0", address.port())]);
+
+        let requests = requests.lock();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("authorization: Bearer secret"));
+        assert!(requests[0].contains("cookie: session=secret; theme=dark"));
+        assert!(requests[1].contains(&format!("host: cdn.test:{}", address.port())), "{}", requests[1]);
+        assert!(!requests[1].contains("secret"), "{}", requests[1]);
+        assert!(!requests[1].contains("cookie"), "{}", requests[1]);
+        assert!(requests[1].contains("x-trace: 7"));
+        assert!(requests[1].contains("user-agent: agent/1"));
+    }
+
+    /// Hands out a `stale` token until first asked to refresh, then a `fresh` one. Records
+    /// the `refresh` argument of every call.
+    struct RefreshingProvider(Mutex<Vec<bool>>);
+
+    impl CredentialProvider for RefreshingProvider {
+        fn credentials(&self, refresh: bool) -> BoxFuture<'_, crate::error::Result<Credentials>> {
+            let mut calls = self.0.lock();
+            calls.push(refresh);
+            let token = match calls.contains(&true) {
+                true => "fresh",
+                false => "stale",
+            };
+            Box::pin(async move { Ok(Credentials::Bearer(token.to_string())) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rejected_credentials() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let base = serve(requests.clone()).await;
+        let provider = Arc::new(RefreshingProvider(Mutex::new(Vec::new())));
+        let service = Arc::new(DownloadService::new());
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+
+        let config = DownloadConfiguration::new()
+            .set_url(&format!("{}/auth", base))
+            .set_download_in_memory(true)
+            .set_credential_provider(provider.clone())
+            .set_retry_times_on_failure(1)
+            .build()
+            .unwrap();
+        let operation = service.add_downloader(config);
+        while !operation.is_done() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        handle.abort();
+
+        assert_eq!(operation.status(), DownloadStatus::Complete);
+        // The rejected request is retried once, with credentials refreshed for it.
+        let calls = provider.0.lock().clone();
+        let refreshed = calls.iter().position(|refresh| *refresh).unwrap();
+        assert_eq!(refreshed, 1, "{:?}", calls);
+        let requests = requests.lock();
+        assert!(requests[0].contains("authorization: Bearer stale"));
+        assert!(requests.iter().skip(1).all(|head| head.contains("authorization: Bearer fresh")), "{:?}", requests);
+    }
+}
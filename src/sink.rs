@@ -0,0 +1,537 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use parking_lot::Mutex;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::sync::OwnedSemaphorePermit;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use tokio_util::sync::CancellationToken;
+use crate::body_stream::ChunkItem;
+use crate::compression::Compression;
+use crate::download_sender::DownloadSender;
+use crate::error::DownloadError;
+use crate::memory_budget::{Budgeted, Payload, Reservation, SpilledFile};
+use crate::stream::Stream;
+
+/// Destination of the bytes of one chunk of a download.
+///
+/// A sink is opened before the first request of its chunk, receives the chunk's
+/// bytes in order and is finalized once the whole range has arrived. Downloads to a
+/// file or into memory use built-in sinks; a [`SinkFactory`] set with
+/// [`set_sink`](crate::download_configuration::DownloadConfigurationBuilder::set_sink)
+/// replaces them, for example to write into an encrypted store or an object store.
+///
+/// ```
+/// use futures::future::BoxFuture;
+/// use downloader_rs::sink::DownloadSink;
+///
+/// struct Discard;
+///
+/// impl DownloadSink for Discard {
+///     fn open(&mut self, _append: bool) -> BoxFuture<'_, downloader_rs::error::Result<()>> {
+///         Box::pin(async { Ok(()) })
+///     }
+///     fn write_at<'a>(&'a mut self, _offset: u64, _buffer: &'a [u8]) -> BoxFuture<'a, downloader_rs::error::Result<()>> {
+///         Box::pin(async { Ok(()) })
+///     }
+///     fn flush(&mut self) -> BoxFuture<'_, downloader_rs::error::Result<()>> {
+///         Box::pin(async { Ok(()) })
+///     }
+///     fn finalize(&mut self) -> BoxFuture<'_, downloader_rs::error::Result<()>> {
+///         Box::pin(async { Ok(()) })
+///     }
+///     fn abort(&mut self) -> BoxFuture<'_, downloader_rs::error::Result<()>> {
+///         Box::pin(async { Ok(()) })
+///     }
+/// }
+/// ```
+pub trait DownloadSink: Send + Sync {
+    /// Prepare to receive bytes. With `append` the data already held is kept and the
+    /// chunk continues after it; otherwise the sink starts empty. `open` is called
+    /// again without `append` when a retry receives the whole body from the start.
+    fn open(&mut self, append: bool) -> BoxFuture<'_, crate::error::Result<()>>;
+
+    /// Write `buffer` at `offset` in the remote file. Bytes of one chunk arrive in
+    /// order, so a sink per chunk can simply append them.
+    fn write_at<'a>(&'a mut self, offset: u64, buffer: &'a [u8]) -> BoxFuture<'a, crate::error::Result<()>>;
+
+    /// Persist the bytes written so far, e.g. before the download is stopped.
+    fn flush(&mut self) -> BoxFuture<'_, crate::error::Result<()>>;
+
+    /// Called once every byte of the chunk has been written.
+    fn finalize(&mut self) -> BoxFuture<'_, crate::error::Result<()>>;
+
+    /// Called when the chunk failed. The sink releases its resources and may keep the
+    /// bytes written so far if it can resume them.
+    fn abort(&mut self) -> BoxFuture<'_, crate::error::Result<()>>;
+}
+
+/// Creates the [`DownloadSink`] of every chunk of a download.
+///
+/// Chunks of one download are written concurrently, each through its own sink.
+pub trait SinkFactory: Send + Sync {
+    /// Create the sink of chunk `index`, which covers bytes `start..=end` of the remote file.
+    fn create(&self, index: usize, start: u64, end: u64) -> crate::error::Result<Box<dyn DownloadSink>>;
+}
+
+/// Writes a chunk to a file, decoding it first when it is decompressed on the fly.
+pub(crate) struct FileSink {
+    path: PathBuf,
+    decompression: Option<Compression>,
+    stream: Option<Stream>,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf, decompression: Option<Compression>) -> Self {
+        Self {
+            path,
+            decompression,
+            stream: None,
+        }
+    }
+}
+
+impl DownloadSink for FileSink {
+    fn open(&mut self, append: bool) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async move {
+            self.stream = None;
+            let stream = match self.decompression {
+                Some(compression) => Stream::with_decoder(&self.path, compression).await?,
+                None => Stream::new(&self.path, append).await?,
+            };
+            self.stream = Some(stream);
+            Ok(())
+        })
+    }
+
+    fn write_at<'a>(&'a mut self, _offset: u64, buffer: &'a [u8]) -> BoxFuture<'a, crate::error::Result<()>> {
+        Box::pin(async move {
+            if let Some(stream) = &mut self.stream {
+                stream.write_async(buffer).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async move {
+            if let Some(stream) = &mut self.stream {
+                stream.flush_async().await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn finalize(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async move {
+            if let Some(stream) = &mut self.stream {
+                stream.finish_async().await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The file is kept so that a later download resumes from it.
+    fn abort(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async move {
+            if let Some(mut stream) = self.stream.take() {
+                stream.flush_async().await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Most bytes reserved up front for an in-memory download. Beyond it the buffer grows as
+/// bytes arrive, so a wrong reported length cannot exhaust memory.
+const PREALLOCATION_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// The buffer all chunks of an in-memory download are written into.
+pub(crate) struct MemoryBuffer {
+    bytes: Mutex<Vec<u8>>,
+    /// The budget's permit for `bytes`, passed on to the published payload.
+    permit: Mutex<Option<OwnedSemaphorePermit>>,
+    /// The file the download is written to instead, if it was spilled.
+    spill: Option<Arc<SpilledFile>>,
+    /// Chunks that have not been finalized yet. The last one publishes the buffer.
+    pending: AtomicUsize,
+    sender: Arc<DownloadSender>,
+}
+
+impl MemoryBuffer {
+    /// A buffer for a download of `total_length` bytes, 0 if unknown, completed by
+    /// `chunk_count` chunks. It holds the bytes up to the furthest one written, so a body
+    /// shorter than reported is not padded.
+    pub fn new(total_length: u64, chunk_count: usize, reservation: Reservation, sender: Arc<DownloadSender>) -> Arc<Self> {
+        let (bytes, permit, spill) = match reservation {
+            Reservation::Memory(permit) => (Vec::with_capacity(total_length.min(PREALLOCATION_LIMIT) as usize), permit, None),
+            Reservation::Spill(file) => (Vec::new(), None, Some(file)),
+        };
+        Arc::new(Self {
+            bytes: Mutex::new(bytes),
+            permit: Mutex::new(permit),
+            spill,
+            pending: AtomicUsize::new(chunk_count),
+            sender,
+        })
+    }
+
+    fn publish(&self) {
+        let payload = match &self.spill {
+            Some(file) => Payload::File(file.clone()),
+            None => {
+                let bytes = std::mem::take(&mut *self.bytes.lock());
+                match self.permit.lock().take() {
+                    Some(permit) => Payload::Memory(Bytes::from_owner(Budgeted { bytes, _permit: permit })),
+                    None => Payload::Memory(Bytes::from(bytes)),
+                }
+            }
+        };
+        if let Some(memory_sender) = &self.sender.memory_sender {
+            let _ = memory_sender.send(payload);
+        }
+    }
+}
+
+/// Writes a chunk of an in-memory download into the shared [`MemoryBuffer`] by offset,
+/// or into its spill file.
+pub(crate) struct MemorySink {
+    buffer: Arc<MemoryBuffer>,
+    file: Option<File>,
+    /// Offset the spill file is positioned at.
+    position: u64,
+}
+
+impl MemorySink {
+    pub fn new(buffer: Arc<MemoryBuffer>) -> Self {
+        Self {
+            buffer,
+            file: None,
+            position: 0,
+        }
+    }
+}
+
+impl DownloadSink for MemorySink {
+    fn open(&mut self, _append: bool) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async move {
+            if let (Some(spill), None) = (&self.buffer.spill, &self.file) {
+                // Chunks share the file, so it is neither truncated nor appended to.
+                let file = OpenOptions::new().create(true).truncate(false).write(true).open(spill.path()).await
+                    .map_err(|_| DownloadError::OpenOrCreateFile)?;
+                self.file = Some(file);
+                self.position = 0;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_at<'a>(&'a mut self, offset: u64, buffer: &'a [u8]) -> BoxFuture<'a, crate::error::Result<()>> {
+        if self.file.is_none() {
+            let start = offset as usize;
+            let end = start + buffer.len();
+            let mut bytes = self.buffer.bytes.lock();
+            if bytes.len() < end {
+                bytes.resize(end, 0);
+            }
+            bytes[start..end].copy_from_slice(buffer);
+            return Box::pin(async { Ok(()) });
+        }
+        Box::pin(async move {
+            let file = self.file.as_mut().unwrap();
+            if self.position != offset {
+                file.seek(SeekFrom::Start(offset)).await.map_err(|_| DownloadError::FileSeek)?;
+            }
+            file.write_all(buffer).await.map_err(|_| DownloadError::FileWrite)?;
+            self.position = offset + buffer.len() as u64;
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async move {
+            if let Some(file) = &mut self.file {
+                file.flush().await.map_err(|_| DownloadError::FileFlush)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn finalize(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async move {
+            if let Some(file) = &mut self.file {
+                file.flush().await.map_err(|_| DownloadError::FileFlush)?;
+            }
+            if self.buffer.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+                self.buffer.publish();
+            }
+            Ok(())
+        })
+    }
+
+    fn abort(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Passes a chunk on to the download's [`BodyStream`](crate::body_stream::BodyStream).
+pub(crate) struct StreamSink {
+    sender: tokio::sync::mpsc::Sender<ChunkItem>,
+    /// Offset of the first byte not sent yet. A retry that receives the body from the
+    /// start again skips the bytes before it.
+    next: u64,
+    /// Stops waiting for a reader that does not read when the download is stopped.
+    cancel_token: CancellationToken,
+}
+
+impl StreamSink {
+    pub fn new(sender: tokio::sync::mpsc::Sender<ChunkItem>, start: u64, cancel_token: CancellationToken) -> Self {
+        Self {
+            sender,
+            next: start,
+            cancel_token,
+        }
+    }
+
+    async fn send(&self, item: ChunkItem) -> crate::error::Result<()> {
+        tokio::select! {
+            result = self.sender.send(item) => result.map_err(|_| DownloadError::BodyStreamClosed),
+            _ = self.cancel_token.cancelled() => Ok(()),
+        }
+    }
+}
+
+impl DownloadSink for StreamSink {
+    fn open(&mut self, _append: bool) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn write_at<'a>(&'a mut self, offset: u64, buffer: &'a [u8]) -> BoxFuture<'a, crate::error::Result<()>> {
+        Box::pin(async move {
+            let skip = self.next.saturating_sub(offset) as usize;
+            if skip >= buffer.len() {
+                return Ok(());
+            }
+            let bytes = Bytes::copy_from_slice(&buffer[skip..]);
+            self.next = offset + buffer.len() as u64;
+            self.send(ChunkItem::Data(bytes)).await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn finalize(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(self.send(ChunkItem::End))
+    }
+
+    fn abort(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::download_configuration::DownloadConfiguration;
+    use crate::download_service::DownloadService;
+    use crate::download_status::DownloadStatus;
+    use crate::memory_budget::MemoryBudget;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_buffer_grows_as_written() {
+        let (sender, receiver) = crate::download_tracker::new(true, false);
+        // A bogus length is not allocated up front.
+        let buffer = MemoryBuffer::new(u64::MAX / 2, 2, Reservation::Memory(None), Arc::new(sender));
+        let mut first = MemorySink::new(buffer.clone());
+        let mut second = MemorySink::new(buffer);
+        second.write_at(4, b"efgh").await.unwrap();
+        first.write_at(0, b"abcd").await.unwrap();
+        first.finalize().await.unwrap();
+        second.finalize().await.unwrap();
+        let payload = receiver.memory_receiver.unwrap().borrow().to_bytes().unwrap();
+        assert_eq!(&payload[..], b"abcdefgh");
+    }
+
+    /// Writes every chunk at its offset into one shared buffer.
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl DownloadSink for SharedBuffer {
+        fn open(&mut self, _append: bool) -> BoxFuture<'_, crate::error::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn write_at<'a>(&'a mut self, offset: u64, buffer: &'a [u8]) -> BoxFuture<'a, crate::error::Result<()>> {
+            let offset = offset as usize;
+            self.0.lock()[offset..offset + buffer.len()].copy_from_slice(buffer);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn flush(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn finalize(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn abort(&mut self) -> BoxFuture<'_, crate::error::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    struct SharedBufferFactory(Arc<Mutex<Vec<u8>>>);
+
+    impl SinkFactory for SharedBufferFactory {
+        fn create(&self, _index: usize, _start: u64, end: u64) -> crate::error::Result<Box<dyn DownloadSink>> {
+            let mut buffer = self.0.lock();
+            if buffer.len() <= end as usize {
+                buffer.resize(end as usize + 1, 0);
+            }
+            Ok(Box::new(SharedBuffer(self.0.clone())))
+        }
+    }
+
+    /// Serve `body`, honouring `Range` requests.
+    pub(crate) async fn serve(body: Arc<Vec<u8>>) -> String {
+        serve_with(body, None, Arc::new(Mutex::new(Vec::new()))).await
+    }
+
+    /// Serve `body` with an `ETag` and a `Last-Modified` date, answering requests that
+    /// send the ETag back with `304 Not Modified`. The request heads are recorded,
+    /// lowercased, in `requests`.
+    pub(crate) async fn serve_validated(
+        body: Arc<Vec<u8>>,
+        etag: &'static str,
+        last_modified: &'static str,
+        requests: Arc<Mutex<Vec<String>>>) -> String {
+        serve_with(body, Some((etag, last_modified)), requests).await
+    }
+
+    async fn serve_with(
+        body: Arc<Vec<u8>>,
+        validators: Option<(&'static str, &'static str)>,
+        requests: Arc<Mutex<Vec<String>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let body = body.clone();
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    let mut buffer = vec![0u8; 4096];
+                    let n = socket.read(&mut buffer).await.unwrap();
+                    let head = String::from_utf8_lossy(&buffer[..n]).to_lowercase();
+                    requests.lock().push(head.clone());
+                    if let Some((etag, _)) = validators {
+                        if head.contains(&format!("if-none-match: {}", etag.to_lowercase())) {
+                            let response = format!("HTTP/1.1 304 Not Modified\r\netag: {}\r\nconnection: close\r\n\r\n", etag);
+                            let _ = socket.write_all(response.as_bytes()).await;
+                            return;
+                        }
+                    }
+                    let range = head.lines()
+                        .find_map(|line| line.strip_prefix("range: bytes="))
+                        .and_then(|range| range.split_once('-'))
+                        .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+                    let (status, data) = match range {
+                        Some((start, end)) => ("206 Partial Content", &body[start..=end]),
+                        None => ("200 OK", &body[..]),
+                    };
+                    let validators = match validators {
+                        Some((etag, last_modified)) => format!("etag: {}\r\nlast-modified: {}\r\n", etag, last_modified),
+                        None => String::new(),
+                    };
+                    let response = format!("HTTP/1.1 {}\r\naccept-ranges: bytes\r\ncontent-length: {}\r\n{}connection: close\r\n\r\n", status, data.len(), validators);
+                    let _ = socket.write_all(response.as_bytes()).await;
+                    if !head.starts_with("head") {
+                        let _ = socket.write_all(data).await;
+                    }
+                });
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn test_chunked_download_to_sink() {
+        let body: Arc<Vec<u8>> = Arc::new((0..100_000u32).map(|i| (i % 251) as u8).collect());
+        let url = serve(body.clone()).await;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let config = DownloadConfiguration::new()
+            .set_url(&url)
+            .set_sink(Arc::new(SharedBufferFactory(received.clone())))
+            .set_chunk_download(true)
+            .set_chunk_size(16 * 1024)
+            .build()
+            .unwrap();
+
+        let service = Arc::new(DownloadService::new());
+        let operation = service.add_downloader(config);
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+        while !operation.is_done() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        handle.abort();
+
+        assert_eq!(operation.status(), DownloadStatus::Complete);
+        assert_eq!(*received.lock(), *body);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_download_into_memory() {
+        let body: Arc<Vec<u8>> = Arc::new((0..100_000u32).map(|i| (i % 241) as u8).collect());
+        let url = serve(body.clone()).await;
+        let config = DownloadConfiguration::new()
+            .set_url(&url)
+            .set_download_in_memory(true)
+            .set_chunk_download(true)
+            .set_chunk_size(16 * 1024)
+            .build()
+            .unwrap();
+
+        let directory = std::env::temp_dir().join(format!("downloader-rs-spill-{}", std::process::id()));
+        let service = Arc::new(DownloadService::new());
+        service.set_memory_budget(MemoryBudget::new(1024 * 1024).set_spill(50_000, &directory));
+        let operation = service.add_downloader(config.clone());
+        let runner = service.clone();
+        let handle = tokio::spawn(async move { runner.run().await });
+        while !operation.is_done() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(operation.status(), DownloadStatus::Complete);
+
+        // Above the spill threshold the payload is written to a file, deleted once dropped.
+        let Payload::File(file) = operation.payload() else { panic!("payload was not spilled") };
+        let path = file.path().to_path_buf();
+        drop(file);
+        assert_eq!(operation.into_bytes(), body[..]);
+        // The service lets go of finished downloads on its next scheduling pass.
+        for _ in 0..50 {
+            if !path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(!path.exists());
+
+        service.set_memory_budget(MemoryBudget::new(1024 * 1024));
+        let operation = service.add_downloader(config);
+        while !operation.is_done() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        handle.abort();
+        assert!(matches!(operation.payload(), Payload::Memory(_)));
+        assert_eq!(operation.into_bytes(), body[..]);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -1,47 +1,46 @@
 use std::path::Path;
 use tokio::fs;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use crate::compression::{self, Compression};
 use crate::error::DownloadError;
 
 pub struct Stream {
+    /// Writes to `file`, through a decoder when the payload is decompressed on the fly.
+    writer: Box<dyn AsyncWrite + Send + Sync + Unpin>,
     file: File,
 }
 
 impl Stream {
     pub async fn new(path: impl AsRef<Path>, append: bool) -> crate::error::Result<Stream> {
-        let path = path.as_ref();
-        if let Some(parent) = path.parent() {
-            if parent.symlink_metadata().is_err() {
-                let _ = fs::create_dir_all(parent).await;
-            }
-        }
-        match OpenOptions::new().
-            create(true).
-            write(true).
-            append(append).
-            open(path).await {
-            Ok(file) => {
-                Ok(Stream {
-                    file,
-                })
-            }
-            Err(_e) => {
-                Err(DownloadError::OpenOrCreateFile)
-            }
-        }
+        let file = open(path.as_ref(), append).await?;
+        let writer = file.try_clone().await.map_err(|_| DownloadError::OpenOrCreateFile)?;
+        Ok(Stream {
+            writer: Box::new(writer),
+            file,
+        })
+    }
+
+    /// Open `path` from the start, decoding everything written with `compression`.
+    pub async fn with_decoder(path: impl AsRef<Path>, compression: Compression) -> crate::error::Result<Stream> {
+        let file = open(path.as_ref(), false).await?;
+        let writer = file.try_clone().await.map_err(|_| DownloadError::OpenOrCreateFile)?;
+        Ok(Stream {
+            writer: compression::decoder(compression, writer)?,
+            file,
+        })
     }
 
     pub async fn write_async(&mut self, buffer: &[u8]) -> crate::error::Result<()> {
-        if let Err(_e) = self.file.write_all(buffer).await {
-            return Err(DownloadError::FileWrite);
+        if let Err(e) = self.writer.write_all(buffer).await {
+            return Err(write_error(e, DownloadError::FileWrite));
         }
 
         Ok(())
     }
 
     pub async fn flush_async(&mut self) -> crate::error::Result<()> {
-        if let Err(_e) = self.file.flush().await {
+        if let Err(_e) = self.writer.flush().await {
             return Err(DownloadError::FileFlush);
         }
 
@@ -51,4 +50,43 @@ impl Stream {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Write out everything once the whole body has been received, including the end
+    /// of a decoded stream.
+    pub async fn finish_async(&mut self) -> crate::error::Result<()> {
+        if let Err(e) = self.writer.shutdown().await {
+            return Err(write_error(e, DownloadError::FileFlush));
+        }
+
+        if let Err(_e) = self.file.sync_all().await {
+            return Err(DownloadError::FileFlush);
+        }
+
+        Ok(())
+    }
+}
+
+/// Report malformed compressed data as a decompression error rather than an I/O error.
+fn write_error(e: std::io::Error, io_error: DownloadError) -> DownloadError {
+    match e.kind() {
+        std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => DownloadError::Decompress(e.to_string()),
+        _ => io_error,
+    }
+}
+
+async fn open(path: &Path, append: bool) -> crate::error::Result<File> {
+    if let Some(parent) = path.parent() {
+        if parent.symlink_metadata().is_err() {
+            let _ = fs::create_dir_all(parent).await;
+        }
+    }
+    match OpenOptions::new().
+        create(true).
+        write(true).
+        append(append).
+        truncate(!append).
+        open(path).await {
+        Ok(file) => Ok(file),
+        Err(_e) => Err(DownloadError::OpenOrCreateFile),
+    }
+}
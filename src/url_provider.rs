@@ -0,0 +1,12 @@
+use futures::future::BoxFuture;
+
+/// Supplies fresh URLs for downloads whose URL expires, such as pre-signed object
+/// store links.
+///
+/// The provider is called when a request is rejected with `401 Unauthorized` or
+/// `403 Forbidden`, and before a request once the URL is older than the configured
+/// expiry. `expired` is the URL that stopped working. The new URL must point to the
+/// same content so that chunks already on disk stay valid.
+pub trait UrlProvider: Send + Sync {
+    fn refresh_url(&self, expired: &str) -> BoxFuture<'_, crate::error::Result<String>>;
+}
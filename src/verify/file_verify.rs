@@ -1,15 +1,27 @@
 use std::path::Path;
+use serde::{Deserialize, Serialize};
 use xxhash_rust::xxh64;
 use tokio::io::{AsyncReadExt, BufReader};
 use crate::error::DownloadError;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 pub enum FileVerify {
     None,
     #[allow(non_camel_case_types)]
     xxHash(u64),
 }
 
+/// Which bytes [`FileVerify`] checks when a download is decompressed.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub enum VerifyTarget {
+    /// The payload as sent by the server.
+    #[default]
+    Compressed,
+    /// The decompressed file written to the destination.
+    Decompressed,
+}
+
+#[allow(clippy::needless_return, clippy::while_let_loop)]
 pub async fn calculate_file_xxhash(file_path: impl AsRef<Path>, seed: u64) -> crate::error::Result<u64> {
     match tokio::fs::File::open(file_path.as_ref()).await {
         Ok(file) => {